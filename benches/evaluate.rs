@@ -0,0 +1,64 @@
+//! Times parsing and evaluating synthetic workbooks of growing size, to check that
+//! both stay linear in the number of cells. Run with `cargo bench`; pass a cell count,
+//! e.g. `cargo bench -- 1000000`, to time a single size.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use mycrate::*;
+
+const COLUMNS: usize = 13;
+
+/// A payload of `sheets` sheets with `rows` rows each. Every row holds literals, arithmetic
+/// on its own cells, a running total down the sheet, a short window SUM, a name, a blank
+/// cell and a read of it, and every thousandth row spills a SEQUENCE that the next rows read.
+/// With `linked`, each sheet also reads the one before it.
+fn payload(sheets: usize, rows: usize, linked: bool) -> String {
+    let mut json = String::from(r#"{"submissionUrl": "bench", "names": {"Rate": "sheet-1!A1"}, "sheets": ["#);
+    for s in 1..=sheets {
+        if s > 1 {
+            json.push_str(", ");
+        }
+        write!(json, r#"{{"id": "sheet-{}", "data": ["#, s).unwrap();
+        for i in 1..=rows {
+            if i > 1 {
+                json.push_str(", ");
+            }
+            let total = if i == 1 { "=C1".to_string() } else { format!("=D{}+C{}", i - 1, i) };
+            let window = format!("A{}:A{}", i.saturating_sub(9).max(1), i);
+            let other = if linked && s > 1 { format!("'sheet-{}'!F{}+", s - 1, i) } else { String::new() };
+            let spill = if i % 1000 == 1 { "=SEQUENCE(3)" } else { "" };
+            write!(json, r#"[{i}, "=A{i}*2", "=B{i}+A{i}", "{total}", "=IF(GT(D{i}, 100), D{i}, 0)", "=SUM(A{i}:E{i})", "=F{i}*Rate", "x", "", "={other}SUM({window})", "=I{i}", "{spill}", "=L{i}"]"#).unwrap();
+        }
+        json.push_str("]}");
+    }
+    json.push_str("]}");
+    json
+}
+
+fn run(name: &str, sheets: usize, rows: usize, linked: bool) {
+    let json = payload(sheets, rows, linked);
+    let start = Instant::now();
+    let tokens = Lexer::new(json).lex();
+    let node = Parser::new(tokens).parse_all().unwrap();
+    let parsed = start.elapsed();
+    let start = Instant::now();
+    let workbook = Workbook::new(node, Registry::new(), Settings::default()).unwrap();
+    let evaluated = start.elapsed();
+    let cells = sheets * rows * COLUMNS;
+    assert_eq!(workbook.sheets().iter().map(|s| s.rows().iter().map(|r| r.len()).sum::<usize>()).sum::<usize>(), cells);
+    let per_cell = |d: Duration| d.as_nanos() as f64 / cells as f64;
+    println!("{:<8} {:>9} cells  parse {:>9.1?} ({:>6.0} ns/cell)  evaluate {:>9.1?} ({:>6.0} ns/cell)",
+        name, cells, parsed, per_cell(parsed), evaluated, per_cell(evaluated));
+}
+
+fn main() {
+    let sizes: Vec<usize> = match std::env::args().skip(1).find_map(|a| a.parse().ok()) {
+        Some(cells) => vec![cells],
+        None => vec![10_000, 100_000, 1_000_000],
+    };
+    for cells in sizes {
+        let rows = (cells / COLUMNS / 4).max(1);
+        run("separate", 4, rows, false);
+        run("linked", 4, rows, true);
+    }
+}
@@ -1,40 +1,35 @@
-use std::env;
-use std::fs;
 use regex::Regex;
-use inline_colorization::*;
-use std::cell::RefCell;
-use std::borrow::BorrowMut;
-use serde::__private::de::Borrowed;
-use std::cell::RefMut;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, LazyLock};
+use std::{panic, thread};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+use serde_json::value::RawValue;
 use crate::Node::Notation;
 use crate::Node::BoolLit;
 use crate::Node::StringLit;
 use crate::Node::IntLit;
 use crate::Node::FloatLit;
-use crate::Node::OperationSum;
-use crate::Node::OperationEq;
-use crate::Node::OperationGt;
-use crate::Node::OperationNot;
-use crate::Node::OperationAnd;
-use crate::Node::OperationOr;
-use crate::Node::OperationDivide;
-use crate::Node::OperationMultiply;
-use crate::Node::OperationConcat;
-use crate::Node::OperationIf;
 use crate::Node::CellSequence;
 use crate::Node::DataCells;
-use crate::Node::Sheet;
 use crate::Node::Spreadsheet;
 use crate::Node::Evaluator;
-use core::iter;
 // use std::iter;
 
+/// A cell reference such as `B3` or `$B$3`, compiled once rather than for every identifier.
+static NOTATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\$?[A-Z]+\$?[0-9]+$").unwrap());
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenKind {
     Start, Identifier, String, Int, Double, Assign, Bool, Op_Paren_o, Arr_o, Op_Paren_c, Arr_c, Colon,
-    OP_Brace_o, OP_Brace_c, Semicolon, Comma, Punc, Operation_Sum, Operation_Multiply, Operation_Divide,
-    Operation_And, Operation_Or, Operation_Eq, Operation_Not, Operation_Concat, Operation_Gt, Operation_If,
+    OP_Brace_o, OP_Brace_c, Semicolon, Comma, Punc,
+    Operation_Let, Operation_Lambda, Plus, Minus, Star, Slash, Hash, Bang,
     Id, Data, Sheets , Notation, Submission_url,
+    /// Closes the tokens of a `"=formula"` string in a payload; its literal is the formula as written.
+    Eoo,
     Eof
 }
 
@@ -63,6 +58,8 @@ struct Operation {
         id: usize, 
         tokens: Vec<Token>, 
         buffer: String,
+        /// Set when a character could not be tokenized and was left out.
+        skipped: bool,
 }
 
 
@@ -88,7 +85,8 @@ impl Operation {
                 line: 1,
                 id: 0,
                 tokens: Vec::<Token>::new(),
-                buffer: String::new()
+                buffer: String::new(),
+                skipped: false,
             }
 
     }
@@ -138,7 +136,7 @@ impl Operation {
 
     fn peek(&self) -> Option<char> {
         let next = self.offset + 1;
-        if next > self.body.len() {
+        if next >= self.body.len() {
             return None;
         } else {
             return Some(*self.body.get(next).unwrap());
@@ -170,47 +168,103 @@ impl Operation {
                     self.new_token(TokenKind::Comma);
                     self.id+=1;
                 },
+                '-' if !self.after_operand() && self.peek().is_some_and(|c| c.is_numeric()) => {
+                    self.buffer.push(a);
+                    self.offset+=1;
+                    self.lex_int();
+                    self.id+=1;
+                },
+                ':' => {
+                    self.new_token(TokenKind::Punc);
+                    self.id+=1;
+                },
+                '#' => {
+                    self.new_token(TokenKind::Hash);
+                    self.id+=1;
+                },
+                '!' => {
+                    self.new_token(TokenKind::Bang);
+                    self.id+=1;
+                },
+                '\'' => {
+                    self.lex_sheet_name();
+                    self.id+=1;
+                },
+                '+' | '-' | '*' | '/' => {
+                    self.buffer.push(a);
+                    match a {
+                        '+' => self.new_token(TokenKind::Plus),
+                        '-' => self.new_token(TokenKind::Minus),
+                        '*' => self.new_token(TokenKind::Star),
+                        _ => self.new_token(TokenKind::Slash),
+                    }
+                    self.id+=1;
+                },
                 '"' => {
+                    self.buffer.push(a);
                     self.lex_string();
                     self.id+=1;
                 },
-                '\\' => {
-                    let next = self.peek();
-                    if next.unwrap() == '"' {
-                        self.buffer.push('\"');
-                        self.offset+=1;
-                        self.lex_string();
-                        self.id+=1;
-                    } else {
-                        eprintln!("This is going to standard error!, {}", a);
-                    }
+                '\\' if self.peek() == Some('"') => {
+                    self.buffer.push('\"');
+                    self.offset+=1;
+                    self.lex_string();
+                    self.id+=1;
                 },
                 '\n' => {
                     self.line += 1;
                 },
-                _ if a.is_alphabetic() => {
+                ' ' | '\t' | '\r' => {},
+                _ if a.is_alphabetic() || a == '$' => {
                     self.lex_ident();
                     self.id+=1;
                 },
                 _ => {
-                    eprintln!("This is going to standard error!, {}", a);
+                    self.skipped = true;
                 } 
 
             }
             self.offset+=1;
         }
+        // An unterminated string is still in the buffer.
+        self.skipped |= !self.buffer.is_empty();
 
         // self.tokens.push(Token::new(TokenKind::Eoo, "".to_owned()));
         let tok = self.tokens.clone();
         tok
     }
 
+    /// Like `lex`, but `None` if any of the input was left out of the tokens.
+    fn lex_exact(&mut self) -> Option<Vec<Token>> {
+        let tokens = self.lex();
+        if self.skipped { None } else { Some(tokens) }
+    }
+
+
+    /// A quoted sheet name such as `'sheet-1'` in `'sheet-1'!A1`.
+    fn lex_sheet_name(&mut self) {
+        self.offset+=1;
+        while self.offset != self.body.len() && self.char_at() != '\'' {
+            self.buffer.push(self.char_at());
+            self.offset+=1;
+        }
+        self.new_identifier_token();
+    }
+
+    /// Whether the last token ends an operand, so a following `-` is a subtraction.
+    fn after_operand(&self) -> bool {
+        match self.tokens.last() {
+            Some(t) => matches!(t.kind, TokenKind::Int | TokenKind::Double | TokenKind::String | TokenKind::Bool
+                | TokenKind::Notation | TokenKind::Identifier | TokenKind::Op_Paren_c),
+            None => false,
+        }
+    }
 
     fn lex_ident(&mut self){
         let mut currChar;
         while self.offset != self.body.len() {
             currChar = self.char_at();
-            if(currChar.is_alphabetic()) || (currChar.is_numeric()) {
+            if(currChar.is_alphabetic()) || (currChar.is_numeric()) || (currChar == '_') || (currChar == '$') {
                 self.buffer.push(currChar);
                 self.offset+=1;
             }
@@ -225,23 +279,16 @@ impl Operation {
     }
 
     fn key_check(&mut self) {
-        let key = vec!["IF", "GT", "EQ", "SUM", "MULTIPLY", "CONCAT", "DIVIDE", "NOT", "AND", "OR"];
-        let bools = vec!["true","false"];
-        let types = vec!["int", "float", "bool","string"];
-        let re = Regex::new(r"[A-Z][0-9]+").unwrap();
+        if self.buffer.is_empty() {
+            return;
+        }
+        let key = ["LET", "LAMBDA"];
+        let bools = ["true","false"];
 
         for kw in key.iter() {
             if kw.eq_ignore_ascii_case(&self.buffer.to_owned()) {
-                if *kw == "IF" { self.new_token(TokenKind::Operation_If); self.reset(); return; }
-                if *kw == "GT" { self.new_token(TokenKind::Operation_Gt); self.reset();  return; }
-                if *kw == "EQ" { self.new_token(TokenKind::Operation_Eq); self.reset();  return; }
-                if *kw == "SUM" { self.new_token(TokenKind::Operation_Sum); self.reset();  return; }
-                if *kw == "MULTIPLY" { self.new_token(TokenKind::Operation_Multiply);self.reset();  return; }
-                if *kw == "CONCAT" { self.new_token(TokenKind::Operation_Concat); self.reset(); return; }
-                if *kw == "NOT" { self.new_token(TokenKind::Operation_Not); self.reset(); return; }
-                if *kw == "AND" { self.new_token(TokenKind::Operation_And); self.reset(); return; }
-                if *kw == "OR" { self.new_token(TokenKind::Operation_Or); self.reset(); return; }
-                if *kw == "DIVIDE" { self.new_token(TokenKind::Operation_Divide); self.reset(); return; }
+                if *kw == "LET" { self.new_token(TokenKind::Operation_Let); self.reset(); return; }
+                if *kw == "LAMBDA" { self.new_token(TokenKind::Operation_Lambda); self.reset(); return; }
             }
         }
 
@@ -252,10 +299,11 @@ impl Operation {
             }
         }
 
-        if re.captures(&self.buffer.to_owned()).is_some() {
+        if NOTATION.is_match(&self.buffer) {
             self.new_notation_token(self.buffer.to_owned());
             return;
         }
+        self.new_identifier_token();
     }
 
     fn lex_string(&mut self) {
@@ -271,8 +319,11 @@ impl Operation {
                     self.offset+=1;
                     self.new_string_token();
                     break;
+                } else if currChar=='"' {
+                    self.buffer.push(currChar);
+                    self.new_string_token();
+                    break;
                 } else if currChar=='\n' {
-                    println!("Invalid string!, {}", currChar);
                     break;
                 } else {
                     self.buffer.push(currChar);
@@ -290,9 +341,6 @@ impl Operation {
                 self.buffer.push(currChar);
                 self.offset+=1;
             }
-            else if currChar.is_alphabetic() {
-                println!("Invalid int!, {}", currChar);
-            }
             else if currChar == '.' {
                 self.buffer.push(currChar);
                 self.offset+=1;
@@ -305,6 +353,9 @@ impl Operation {
                     break;
             }
         }
+        if !self.buffer.is_empty() {
+            self.new_int_token();
+        }
     }
 
     fn lex_float(&mut self) {
@@ -315,8 +366,6 @@ impl Operation {
             if currChar.is_numeric() {
                 self.buffer.push(currChar);
                 self.offset+=1;
-            } else if currChar.is_alphabetic() {
-                println!("Invalid double!, {}", currChar);
             }
             else{
                 self.new_double_token();
@@ -324,6 +373,9 @@ impl Operation {
                 break;
             }
         }
+        if !self.buffer.is_empty() {
+            self.new_double_token();
+        }
     }
 }
 
@@ -426,13 +478,18 @@ impl Lexer {
                     self.new_token(TokenKind::Semicolon);
                     self.id+=1;
                 },
+                '-' if self.peek().is_some_and(|c| c.is_numeric()) => {
+                    self.buffer.push(a);
+                    self.offset+=1;
+                    self.lex_int();
+                    self.id+=1;
+                },
                 ':' => {
                     self.new_token(TokenKind::Punc);
                     self.id+=1;
                 },
                 '"' => {
-                    let next = self.peek();
-                    if next.unwrap() == '=' {
+                    if self.peek() == Some('=') {
                         self.lex_op()
                     } else {
                         self.lex_string();
@@ -446,6 +503,7 @@ impl Lexer {
                 '\n' => {
                     self.line += 1;
                 },
+                ' ' | '\t' | '\r' => {},
                 _ => {
                     eprintln!("This is going to standard error!, {}", a);
                 } 
@@ -466,7 +524,7 @@ impl Lexer {
 
     fn peek(&self) -> Option<char> {
         let next = self.offset + 1;
-        if next > self.body.len() {
+        if next >= self.body.len() {
             return None;
         } else {
             return Some(*self.body.get(next).unwrap());
@@ -490,10 +548,8 @@ impl Lexer {
     }
 
     fn key_check(&mut self) {
-        let key = vec!["data", "sheets", "id", "submissionUrl"];
-        let bools = vec!["true","false"];
-        let types = vec!["int", "float", "bool","string"];
-        let re = Regex::new(r"[A-Z][0-9]+").unwrap();
+        let key = ["data", "sheets", "id", "submissionUrl"];
+        let bools = ["true","false"];
 
         for kw in key.iter() {
             if kw.eq_ignore_ascii_case(&self.buffer.to_owned()) {
@@ -525,10 +581,8 @@ impl Lexer {
                     self.new_string_token();
                     break;
                 }else if currChar=='\n' {
-                    println!("Invalid string!, {}", currChar);
                     break;
                 }else if currChar=='\\' {
-                    println!("Invalid string!, {}", currChar);
                     break;
                 } else {
                     self.buffer.push(currChar);
@@ -549,11 +603,10 @@ impl Lexer {
                 self.reset();
                 break;
             }else if currChar=='\\' {
-                let next = self.peek();
-                if next.unwrap() == '"' {
+                if self.peek() == Some('"') {
                     self.buffer.push(currChar);
                     self.offset+=1;
-                    self.buffer.push(next.unwrap());
+                    self.buffer.push('"');
                     self.offset+=1;
                 } else {
                     self.buffer.push(currChar);
@@ -561,7 +614,6 @@ impl Lexer {
                     break;
                 }
             } else if currChar=='\n' {
-                println!("Invalid string!, {}", currChar);
                 break;
             } else {
                 self.buffer.push(currChar);
@@ -570,13 +622,15 @@ impl Lexer {
         }
     }
 
+    /// Splices the formula's tokens into the payload's, closed by an `Eoo`. A formula with
+    /// characters `Operation` cannot lex adds only the `Eoo`, for the parser to report.
     fn check_inside(&mut self) {
         let cvec = self.buffer.to_owned();
-        let mut op = Operation::new(cvec); 
-        let tok = op.lex();
-        for element in tok.iter() {
-            self.tokens.push(element.clone());
+        let mut op = Operation::new(cvec.clone()); 
+        if let Some(tok) = op.lex_exact() {
+            self.tokens.extend(tok);
         }
+        self.tokens.push(Token::new(TokenKind::Eoo, cvec));
     }
 
     fn lex_int(&mut self) {
@@ -588,8 +642,9 @@ impl Lexer {
                 self.buffer.push(currChar);
                 self.offset+=1;
             }
-            else if currChar.is_alphabetic() {
-                println!("Invalid int!, {}", "awesome");
+            else if self.exponent_follows() {
+                self.lex_exponent();
+                break;
             }
             else if currChar == '.' {
                 self.buffer.push(currChar);
@@ -613,8 +668,9 @@ impl Lexer {
             if currChar.is_numeric() {
                 self.buffer.push(currChar);
                 self.offset+=1;
-            } else if currChar.is_alphabetic() {
-                println!("Invalid double!, {}", "awesome");
+            } else if self.exponent_follows() {
+                self.lex_exponent();
+                break;
             }
             else{
                 self.new_double_token();
@@ -624,34 +680,87 @@ impl Lexer {
         }
     }
 
-}
+    /// Whether the number being lexed goes on with an exponent, as in `1e21` or `2.5E-7`.
+    fn exponent_follows(&self) -> bool {
+        let digit = |at: usize| self.body.get(at).is_some_and(|c| c.is_ascii_digit());
+        matches!(self.body.get(self.offset), Some('e' | 'E'))
+            && (digit(self.offset + 1) || matches!(self.body.get(self.offset + 1), Some('+' | '-')) && digit(self.offset + 2))
+    }
+
+    /// The exponent of a number, which makes it a `Double` whatever came before it.
+    fn lex_exponent(&mut self) {
+        self.buffer.push(self.char_at());
+        self.offset+=1;
+        if matches!(self.char_at(), '+' | '-') {
+            self.buffer.push(self.char_at());
+            self.offset+=1;
+        }
+        while self.offset != self.body.len() && self.char_at().is_ascii_digit() {
+            self.buffer.push(self.char_at());
+            self.offset+=1;
+        }
+        self.new_double_token();
+        self.offset-=1;
+    }
 
-#[derive(Debug, PartialEq)]
-pub enum Response {
-    DataCells(Box<ResultsData>),
-    Sheet(Box<ResultsSheet>),
-    Spreadsheet(Box<SpreadsheetResponseNode>),
-    Evaluator(Box<EvaluatorResponseNode>),
-}
-#[derive(Debug, PartialEq)]
-pub struct EvaluatorResponseNode {
-    pub values: Box<Option<Response>>,
 }
-#[derive(Debug, PartialEq)]
-pub struct SpreadsheetResponseNode {
-    pub values: Vec<Box<Option<Response>>>,
+
+/// One evaluated cell as it is submitted: an integer as a JSON number, a float as
+/// `{"type": "float", "value": 5}`, or a JSON boolean, string or error object.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultsCell {
+    Int(i64),
+    Number(f64),
+    Bool(bool),
+    Text(String),
+    Error(CellError),
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ResultsSheet {
-    pub id: String,
-    pub values: Box<Option<Response>>,
+impl From<Value> for ResultsCell {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Int(n) => ResultsCell::Int(n),
+            Value::Number(n) if n.is_finite() => ResultsCell::Number(n),
+            Value::Number(_) => ResultsCell::Error(CellError { kind: ErrorKind::Num, detail: None }),
+            Value::Bool(b) => ResultsCell::Bool(b),
+            Value::Text(s) => ResultsCell::Text(s),
+            Value::Lambda(_) => ResultsCell::Error(CellError { kind: ErrorKind::Calc, detail: Some("cell holds a LAMBDA".to_string()) }),
+            Value::Array(a) => match a.first().and_then(|r| r.first()) {
+                Some(v) => ResultsCell::from(v.clone()),
+                None => ResultsCell::Error(CellError { kind: ErrorKind::Calc, detail: None }),
+            },
+            Value::Error(e) => ResultsCell::Error(e),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ResultsData {
-    pub data: String,
-    pub cells: Vec<String>,
+impl Serialize for ResultsCell {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ResultsCell::Int(n) => serializer.serialize_i64(*n),
+            // JSON.parse cannot tell 5.0 from 5, so floats say what they are, with the digits
+            // JavaScript would print written as raw JSON.
+            ResultsCell::Number(n) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("type", "float")?;
+                match RawValue::from_string(js_number(*n)) {
+                    Ok(raw) => map.serialize_entry("value", &raw)?,
+                    Err(_) => map.serialize_entry("value", n)?,
+                }
+                map.end()
+            },
+            ResultsCell::Bool(b) => serializer.serialize_bool(*b),
+            ResultsCell::Text(s) => serializer.serialize_str(s),
+            ResultsCell::Error(e) => {
+                let mut map = serializer.serialize_map(None)?;
+                map.serialize_entry("error", e.kind.code())?;
+                if let Some(detail) = &e.detail {
+                    map.serialize_entry("detail", detail)?;
+                }
+                map.end()
+            },
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -661,16 +770,15 @@ pub enum Node {
     IntLit(LitNode),
     FloatLit(LitNode),
     Notation(NotationNode),
-    OperationSum(SumNode),
-    OperationEq(EqNode),
-    OperationGt(GtNode),
-    OperationMultiply(MultiplyNode),
-    OperationAnd(AndNode),
-    OperationNot(NotNode),
-    OperationOr(OrNode),
-    OperationIf(IfNode),
-    OperationConcat(ConcatNode),
-    OperationDivide(DivideNode),
+    Identifier(LitNode),
+    Call(CallNode),
+    Invalid(InvalidNode),
+    OperationLet(LetNode),
+    OperationLambda(LambdaNode),
+    Apply(ApplyNode),
+    Binary(BinaryNode),
+    Range(RangeNode),
+    SpillRef(NotationNode),
     CellSequence(CellSequenceNode),
     DataCells(DataNode),
     Sheet(SheetNode),
@@ -683,9 +791,17 @@ pub enum Node {
 #[derive(Debug, PartialEq)]
 pub struct EvaluatorNode {
     pub url: Token,
+    pub names: Box<[NameNode]>,
     pub values: Box<[Node]>,
 }
 
+/// A workbook-level name from the payload's `names` object, e.g. a reusable LAMBDA.
+#[derive(Debug, PartialEq)]
+pub struct NameNode {
+    pub token: Token,
+    pub value: Box<Node>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct SpreadsheetNode {
     pub values: Box<[Node]>,
@@ -700,872 +816,2924 @@ pub struct SheetNode {
 #[derive(Debug, PartialEq)]
 pub struct DataNode {
     pub values: Box<[Node]>,
-    pub mem: Option<Vec<String>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct CellSequenceNode {
     pub values: Box<[Node]>,
-    pub mem: Option<Vec<String>>,
-}
-
-impl CellSequenceNode {
-    pub fn add_mem(&mut self, mem:Vec<String>) {
-        self.mem = Some(mem.clone());
-    }
+    /// Each cell's text as written, formulas re-rendered from their tokens.
+    pub sources: Vec<String>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct NotationNode {
     pub token: Token,
-    pub slot: String,
+    pub reference: CellRef,
+    /// The `sheet-1!` part of a reference into another sheet.
+    pub sheet: Option<Token>,
 }
 
+/// A call of a function by name, e.g. `SUM(A1, 2)`; the name is resolved when the cell is evaluated.
 #[derive(Debug, PartialEq)]
-pub struct SumNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+pub struct CallNode {
+    pub name: Token,
+    pub args: Box<[Node]>,
+    /// Positions of the call's first and one-past-last token in the parser's token stream.
+    pub span: Range<usize>,
 }
 
+/// `LET(name1, value1, ..., body)`; each value can see the names bound before it.
 #[derive(Debug, PartialEq)]
-pub struct EqNode {
+pub struct LetNode {
+    pub names: Vec<Token>,
     pub values: Box<[Node]>,
+    pub body: Box<Node>,
     pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
 }
 
 #[derive(Debug, PartialEq)]
-pub struct NotNode {
-    pub values: Box<[Node]>,
+pub struct LambdaNode {
+    pub params: Vec<Token>,
+    pub body: Arc<Node>,
     pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
 }
 
+/// A call that validation found can never succeed; it evaluates to its message as an error.
 #[derive(Debug, PartialEq)]
-pub struct OrNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+pub struct InvalidNode {
+    pub message: String,
+    pub span: Range<usize>,
 }
 
+/// A call of a value that is not a plain name, e.g. `LAMBDA(a, a)(1)` or `F(1)(2)`.
 #[derive(Debug, PartialEq)]
-pub struct DivideNode {
+pub struct ApplyNode {
+    pub callee: Box<Node>,
     pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
 }
 
+/// Infix arithmetic: `+`, `-`, `*` and `/`.
 #[derive(Debug, PartialEq)]
-pub struct ConcatNode {
-    pub values: Box<[Node]>,
+pub struct BinaryNode {
     pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+    pub lhs: Box<Node>,
+    pub rhs: Box<Node>,
 }
 
+/// A rectangular reference such as `A1:B3`.
 #[derive(Debug, PartialEq)]
-pub struct MultiplyNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+pub struct RangeNode {
+    pub from: CellRef,
+    pub to: CellRef,
+    pub sheet: Option<Token>,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct AndNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+/// A parsed cell reference; `$` marks its column or row as absolute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellRef {
+    pub row: usize,
+    pub col: usize,
+    pub row_abs: bool,
+    pub col_abs: bool,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct GtNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+impl CellRef {
+    /// Parses `B3`, `$B3`, `B$3` or `$B$3` into zero-based row and column; `None` past
+    /// the last row or column a sheet can have.
+    pub fn parse(text: &str) -> Option<CellRef> {
+        let col_abs = text.starts_with('$');
+        let rest = text.strip_prefix('$').unwrap_or(text);
+        let letters = rest.chars().take_while(|c| c.is_ascii_uppercase()).count();
+        if letters == 0 {
+            return None;
+        }
+        let col = rest[..letters].chars()
+            .try_fold(0usize, |acc, c| acc.checked_mul(26)?.checked_add(c as usize - 'A' as usize + 1))
+            .filter(|n| *n <= MAX_COLUMNS)? - 1;
+        let rest = &rest[letters..];
+        let row_abs = rest.starts_with('$');
+        let rest = rest.strip_prefix('$').unwrap_or(rest);
+        if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let row = rest.parse::<usize>().ok().filter(|r| *r > 0 && *r <= MAX_ROWS)? - 1;
+        Some(CellRef { row, col, row_abs, col_abs })
+    }
+
+    /// Moves the relative parts of the reference; `None` if it would leave the sheet.
+    pub fn offset(&self, rows: isize, cols: isize) -> Option<CellRef> {
+        let row = if self.row_abs { self.row } else { self.row.checked_add_signed(rows).filter(|r| *r < MAX_ROWS)? };
+        let col = if self.col_abs { self.col } else { self.col.checked_add_signed(cols).filter(|c| *c < MAX_COLUMNS)? };
+        Some(CellRef { row, col, ..*self })
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct IfNode {
-    pub values: Box<[Node]>,
-    pub token: Token,
-    pub slot: String,
-    pub mem: Option<Vec<String>>,
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut letters = String::new();
+        let mut n = self.col + 1;
+        while n > 0 {
+            letters.insert(0, (b'A' + ((n - 1) % 26) as u8) as char);
+            n = (n - 1) / 26;
+        }
+        let col_abs = if self.col_abs { "$" } else { "" };
+        let row_abs = if self.row_abs { "$" } else { "" };
+        write!(f, "{}{}{}{}", col_abs, letters, row_abs, self.row + 1)
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct LitNode {
     pub token: Token,
-    pub slot: String,
 }
 
 
-#[derive(Debug)]
-pub struct Interpreter {
-    pub mem: Vec<String>,
-    pub val: Vec<String>,
+/// Deepest chain of LAMBDA calls a single cell may build before it is
+/// reported as an error instead of overflowing the stack.
+const MAX_CALL_DEPTH: usize = 256;
+const MAX_SETTLE_ROUNDS: usize = 64;
+/// The largest sheet an array can spill over, as in common spreadsheets.
+const MAX_ROWS: usize = 1_048_576;
+const MAX_COLUMNS: usize = 16_384;
+/// Most values one array function may build, so a single cell cannot exhaust memory.
+const MAX_ARRAY_CELLS: usize = 1 << 20;
+/// Fewest formulas of one dependency level worth handing to a thread of their own.
+const MIN_LEVEL_CHUNK: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// A whole number kept exact; arithmetic that overflows it falls back to `Number`.
+    Int(i64),
+    Number(f64),
+    Bool(bool),
+    Text(String),
+    Lambda(Arc<Closure>),
+    /// Rows of a dynamic array; spills into neighbouring cells when it is a cell's result.
+    Array(Arc<Vec<Vec<Value>>>),
+    Error(CellError),
 }
 
-impl Interpreter {
-    pub fn new(mem: Vec<String>, val: Vec<String>) -> Self {
+impl Value {
+    pub fn error(kind: ErrorKind) -> Value {
+        Value::Error(CellError { kind, detail: None })
+    }
 
-        Self { 
-                mem: mem,
-                val: val,
-            }
+    pub fn error_with(kind: ErrorKind, detail: &str) -> Value {
+        Value::Error(CellError { kind, detail: Some(detail.to_string()) })
     }
+}
 
-    pub fn exec_one(&mut self, frame: usize, one: Vec<String>, mem: Vec<String>, val: Vec<String>) -> Vec<String> {
-        let mut i = 0;
-        let re = Regex::new(r"[A-Z][0-9]+").unwrap();
-        let mut stack:Vec<String> = Vec::new();
-
-        while i < one.len() {
-
-            let op = one.get(i).unwrap();
-            match op.as_str() {
-                "SUM" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "CONCAT" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "OR" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "IF" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "AND" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "NOT" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "GT" => {
-                    i+=1;
-
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "EQ" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "DIVIDE" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                "MULTIPLY" => {
-                    i+=1;
-                    let mut f = frame.clone();
-                    let mut stack_mem:Vec<String> = Vec::new();
-                    let mut stack_val:Vec<String> = Vec::new();
-                    while f < mem.len() {
-                        stack_mem.push(mem.get(f).unwrap().to_string());
-                        stack_val.push(val.get(f).unwrap().to_string());
-                        f+=1;
-                    }
-                    let r = self.exec(stack_mem, stack_val);
-                    stack.push(r.get(0).unwrap().to_string());
-                },
-                _ if op.parse::<f64>().is_ok() => {
-                    stack.push(op.to_string());
-                    i+=1;
-                },
-                _ if re.captures(op).is_some() => {
-                    let mut index = 0;
-                    while index < self.mem.len() {
-                        if self.mem.get(index).unwrap() == op {
-                            break;
-                        }
-                        index+=1;
-                    }
-                    stack.push(self.val.get(index).unwrap().to_string());
-                    i+=1;
-                },
-                _ if op.chars().all(char::is_alphanumeric)|| op.contains(char::is_whitespace) || op.contains("\"") || op.contains(",")=> {
-                    stack.push(op.to_string());
-                    i+=1;
-                },
-                _ => {
-                    i+=1;
-                }
-            }
+/// The error values a cell can hold, as spreadsheets spell them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// `#DIV/0!`: division by zero.
+    Div0,
+    /// `#VALUE!`: an argument of the wrong type or count.
+    Value,
+    /// `#REF!`: a reference to a missing sheet or a circular reference.
+    Ref,
+    /// `#NAME?`: an unknown function or name.
+    Name,
+    /// `#N/A`: no value is available, e.g. past the end of a shorter array.
+    NA,
+    /// `#NUM!`: a result that is not a finite number, or an iteration that did not converge.
+    Num,
+    /// `#SPILL!`: a dynamic array whose spill range is not empty.
+    Spill,
+    /// `#CALC!`: an array function with an empty result.
+    Calc,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Div0 => "#DIV/0!",
+            ErrorKind::Value => "#VALUE!",
+            ErrorKind::Ref => "#REF!",
+            ErrorKind::Name => "#NAME?",
+            ErrorKind::NA => "#N/A",
+            ErrorKind::Num => "#NUM!",
+            ErrorKind::Spill => "#SPILL!",
+            ErrorKind::Calc => "#CALC!",
         }
-      
-        stack
     }
+}
 
-    pub fn exec(&mut self, mem: Vec<String>, val: Vec<String>) -> Vec<String> {
-        let mut i = 0;
-        let mut res1:Vec<String> = Vec::new();
-        let mut res:Vec<String> = Vec::new();
-        let re = Regex::new(r"[A-Z][0-9]+").unwrap();
-        while i < mem.len() {
-
-            let op = val.get(i).unwrap();
-            match op.as_str() {
-                "SUM" => {
-                    i+=1;
-                    let mut sum_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_SUM" {
-                            break;
-                        } else {
-                            sum_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), sum_end, mem.clone(), val.clone());
-                    let mut sum_result = 0 as f64;
-                    for x in stack {
-                        if x.parse::<f64>().is_ok() {
-                            let a = x.parse::<f64>().unwrap();
-                            sum_result = sum_result + a;
-                        } else {
-                            res.push("ERROR: type does not match".to_string());
-                            break;
-                        }
-                    }
-                    i = j;
-                    res.push(sum_result.to_string());
-                },
-                "CONCAT" => {
-                    i+=1;
-                    let mut concat_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_CONCAT" {
-                            break;
-                        } else {
-                            concat_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), concat_end, mem.clone(), val.clone());
-                    let mut concat_res = "".to_string();
-                    for x in stack {
-                        if x.chars().all(char::is_alphanumeric )|| x.contains(char::is_whitespace) || x.contains("\"") || x.contains(",") {
-                            concat_res = concat_res + &x;
-                        } else {
-                            res.push("ERROR: type does not match".to_string());
-                            break;
-                        }
-                    }
-                    i = j;
-                    let s = concat_res.replace("\"", "");
-                    res.push(s);
-                },
-                "OR" => {
-                    i+=1;
-                    let mut or_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_OR" {
-                            break;
-                        } else {
-                            or_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), or_end, mem.clone(), val.clone());
-                    let mut boo = false;
-                    let mut bbb = 1;
-                    for x in stack {
-                        if x != "true" && x != "false" {
-                            bbb = -1;
-                        }
-                        if x == "true" {
-                            boo = true; 
-                        }
-                    }
-                    if bbb > 0 {
-                        res.push(boo.to_string());
-                    } else {
-                        res.push("ERROR: type does not match".to_string());
-                    }
-                    i = j;
-                },
-                "IF" => {
-                    i+=1;
-                    let mut if_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_OR" {
-                            break;
-                        } else {
-                            if_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), if_end, mem.clone(), val.clone());
-                    let mut pop = stack.get(0).unwrap().to_string();
-                    if pop == "true" {
-                        res.push(stack.get(1).unwrap().to_string());
-                    } else if pop == "false" {
-                        res.push(stack.get(2).unwrap().to_string());
-                    } else {
-                        res.push("ERROR: type does not match".to_string());
-                    }
-                    i = j;
-                },
-                "AND" => {
-                    i+=1;
-                    let mut and_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_AND" {
-                            break;
-                        } else {
-                            and_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), and_end, mem.clone(), val.clone());
-                    let mut pop = stack.get(0).unwrap().to_string();
-                    for x in stack {
-                        if x == "false" {
-                            res.push("false".to_string());
-                        } else if x != "true" && x != "false" {
-                            res.push("ERROR: type does not match".to_string());
-                        } else {
-                            res.push("true".to_string());
-                        }
-                    }
-                    i = j;
-                },
-                "NOT" => {
-                    i+=1;
-                    let mut not_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_NOT" {
-                            break;
-                        } else {
-                            not_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), not_end, mem.clone(), val.clone());
-                    let mut x = stack.get(0).unwrap().to_string();
-                    if x != "true" && x != "false" {
-                        res.push("ERROR: type does not match".to_string());
-                    } else if x == "true" {
-                        res.push("true".to_string()); 
-                    } else {
-                        res.push("false".to_string()); 
-                    }
-                    i = j;
-                },
-                "GT" => {
-                    i+=1;
-                    let mut gt_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_GT" {
-                            break;
-                        } else {
-                            gt_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), gt_end, mem.clone(), val.clone());
-                    let mut gt_result = "false".to_string();
-                    let mut pop = stack.get(0).unwrap().to_string();
-                    let mut pop2 = stack.get(1).unwrap().to_string();
-                    if pop.parse::<f64>().is_ok() && pop2.parse::<f64>().is_ok() {
-                        if pop > pop2 {
-                            res.push("true".to_string());
-                        } else {
-                            res.push("false".to_string());
-                        }
-                    } else {
-                        res.push("ERROR: type does not match".to_string());
-                        break;
-                    }
-                    i = j;
-                },
-                "EQ" => {
-                    i+=1;
-                    let mut eq_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_EQ" {
-                            break;
-                        } else {
-                            eq_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), eq_end, mem.clone(), val.clone());
-                    let mut pop = stack.get(0).unwrap().to_string();
-                    let mut pop2 = stack.get(1).unwrap().to_string();
-                    if pop == pop2 {
-                        res.push("true".to_string());
-                    } else {
-                        res.push("false".to_string());
-                    }
-                    i = j;
-                },
-                "DIVIDE" => {
-                    i+=1;
-                    let mut div_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_DIV" {
-                            break;
-                        } else {
-                            div_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), div_end, mem.clone(), val.clone());
-                    let mut pop = stack.get(0).unwrap().to_string();
-                    let mut pop2 = stack.get(1).unwrap().to_string();
-                    if pop.parse::<f64>().is_ok() && pop2.parse::<f64>().is_ok() {
-                        let a = pop.parse::<f64>().unwrap();
-                        let a2 = pop2.parse::<f64>().unwrap();
-                        let mut div_result = a / a2;
-                        res.push(div_result.to_string())
-                    } else {
-                        res.push("ERROR: type does not match".to_string());
-                        break;
-                    }
-
-                    i = j;
-                },
-                "MULTIPLY" => {
-                    i+=1;
-                    let mut mul_end:Vec<String> = Vec::new();
-                    let mut j = i;
-                    while j < mem.len() {
-                        if mem.get(j).unwrap().as_str() == "END_MUL" {
-                            break;
-                        } else {
-                            mul_end.push(val.get(j).unwrap().to_string());
-                        }
-                        j+=1;
-                    }
-                    let stack = self.exec_one(i.clone(), mul_end, mem.clone(), val.clone());
-                    let mut mul_result = 1 as f64;
-                    for x in stack {
-                        if x.parse::<f64>().is_ok() {
-                            let a = x.parse::<f64>().unwrap();
-                            mul_result = mul_result * a;
-                        } else {
-                            res.push("ERROR: type does not match".to_string());
-                            break;
-                        }
-                    }
-                    i = j;
-                    res.push(mul_result.to_string());
-                },
-                _ if op.parse::<f64>().is_ok() => {
-                    res.push(op.to_string());
-                    i+=1;
-                },
-                _ if re.captures(op).is_some() => {
-                    res.push(op.to_string());
-                    i+=1;
-                },
-                _ if op.chars().all(char::is_alphanumeric)=> {
-                    res.push(op.to_string());
-                    i+=1;
-                },
-                _ => {
-                    res.push(op.to_string());
-                    i+=1;
-                }
-            }
+/// An error value with an optional explanation, shown as `#VALUE!` or `#VALUE! (detail)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellError {
+    pub kind: ErrorKind,
+    pub detail: Option<String>,
+}
 
-            // i+=1;
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({})", self.kind.code(), detail),
+            None => write!(f, "{}", self.kind.code()),
         }
-        let mut z = 0;
-        while z < mem.len() {
-            res = self.exec_one(i.clone(), res, mem.clone(), val.clone());
-            z+=1;
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", js_number(*n)),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Lambda(_) => write!(f, "LAMBDA"),
+            Value::Array(a) => match a.first().and_then(|r| r.first()) {
+                Some(v) => write!(f, "{}", v),
+                None => Ok(()),
+            },
+            Value::Error(e) => write!(f, "{}", e),
         }
-        
-        return res
     }
+}
+
+/// Formats a number the way JavaScript's `Number.prototype.toString` does, e.g. `1e+21`,
+/// `1e-7` and `123456789012345680000`, since the submission endpoint compares JS output.
+pub fn js_number(x: f64) -> String {
+    if x.is_nan() {
+        return "NaN".to_string();
+    }
+    if x == 0.0 {
+        return "0".to_string();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    if x < 0.0 {
+        return format!("-{}", js_number(-x));
+    }
+    // ECMAScript wants the fewest digits that round-trip and, among those, the closest to x.
+    // Rust's shortest form gives the count; exact rounding to that count gives the closest.
+    let shortest = format!("{:e}", x);
+    let count = shortest.split_once('e').map_or(1, |(m, _)| m.replace('.', "").len());
+    let text = format!("{:.*e}", count - 1, x);
+    let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    let n = exp.parse::<i32>().unwrap_or(0) + 1;
+    if k <= n && n <= 21 {
+        format!("{}{}", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let sign = if n - 1 < 0 { "-" } else { "+" };
+        let fraction = if k == 1 { String::new() } else { format!(".{}", &digits[1..]) };
+        format!("{}{}e{}{}", &digits[..1], fraction, sign, (n - 1).abs())
+    }
+}
+
+/// A LAMBDA together with the values of the LET variables and parameters visible where it was written.
+#[derive(Debug)]
+pub struct Closure {
+    pub lambda: Arc<Lambda>,
+    /// Those values, in the slots the body reads them from; the parameters take the slots after them.
+    pub frame: Vec<Value>,
+}
 
+/// A compiled LAMBDA.
+#[derive(Debug)]
+pub struct Lambda {
+    pub params: Vec<String>,
+    pub body: Program,
 }
 
-impl Node {
+/// One instruction of a compiled formula. Instructions pop their operands off the
+/// interpreter's stack and push their result; jumps go to an index into the program.
+#[derive(Debug, Clone)]
+pub enum Op {
+    /// Pushes a literal.
+    Push(Value),
+    /// Pushes a cell as a single value, from the formula's sheet or the one named.
+    Load(Option<String>, CellRef),
+    /// Pushes a rectangle of cells as an array, e.g. `A1:B3`.
+    LoadRange(Option<String>, CellRef, CellRef),
+    /// Pushes the whole array a formula cell spilled, e.g. `A1#`.
+    LoadSpill(Option<String>, CellRef),
+    /// Pushes a LET variable or LAMBDA parameter by its slot.
+    Local(usize),
+    /// Pops a value into the next slot, for the next LET variable.
+    Bind,
+    /// Frees the last `n` slots when a LET ends.
+    Unbind(usize),
+    /// Pushes the value of a workbook name.
+    Name(String),
+    /// Pops `n` arguments and calls the registered function of that name.
+    Call(String, usize),
+    /// Pops two operands and applies an infix operator.
+    Operator(String),
+    /// Pops `n` arguments, then a LAMBDA, and calls it.
+    Apply(usize),
+    /// Pushes a LAMBDA closing over the slots in use.
+    Lambda(Arc<Lambda>),
+    /// Turns IF's condition into a boolean, or into the error IF returns and jumps past it.
+    Test(usize),
+    /// Pops a boolean and jumps if it is false.
+    JumpIfFalse(usize),
+    Jump(usize),
+    /// Pops an argument of OR (`true`) or AND (`false`); if it is an error or decides the
+    /// result, pushes that and jumps past the rest.
+    Decide(bool, usize),
+}
 
-    pub fn resolver(&self)-> Box<Option<Response>>{
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sheet = |s: &Option<String>| s.as_ref().map_or(String::new(), |s| format!("'{}'!", s));
         match self {
-            Node::Evaluator(t) => { 
-                let ret = Response::Evaluator(Box::new(EvaluatorResponseNode {values: self.spreadsheet_resolver(&t.values)}));
-                return Box::new(Some(ret))
-            },
-            _=> println!("{color_red}nothing")
+            Op::Push(Value::Text(s)) => write!(f, "push {:?}", s),
+            Op::Push(v) => write!(f, "push {}", v),
+            Op::Load(s, at) => write!(f, "load {}{}", sheet(s), at),
+            Op::LoadRange(s, from, to) => write!(f, "range {}{}:{}", sheet(s), from, to),
+            Op::LoadSpill(s, at) => write!(f, "spill {}{}#", sheet(s), at),
+            Op::Local(slot) => write!(f, "local {}", slot),
+            Op::Bind => write!(f, "bind"),
+            Op::Unbind(n) => write!(f, "unbind {}", n),
+            Op::Name(name) => write!(f, "name {}", name),
+            Op::Call(name, n) => write!(f, "call {}/{}", name, n),
+            Op::Operator(op) => write!(f, "op {}", op),
+            Op::Apply(n) => write!(f, "apply {}", n),
+            Op::Lambda(lambda) => write!(f, "lambda ({})", lambda.params.join(", ")),
+            Op::Test(to) => write!(f, "test {:04}", to),
+            Op::JumpIfFalse(to) => write!(f, "jump-if-false {:04}", to),
+            Op::Jump(to) => write!(f, "jump {:04}", to),
+            Op::Decide(true, to) => write!(f, "or {:04}", to),
+            Op::Decide(false, to) => write!(f, "and {:04}", to),
         }
-        return Box::new(None)
     }
+}
 
-    pub fn spreadsheet_resolver(&self, node:&[Node])-> Box<Option<Response>> {
-        let mut sheets:Vec<Box<Option<Response>>> = Vec::new();
-        for t in node {
-            match t {
-                Node::Spreadsheet(t) => {
-                    sheets.push(self.sheet_resolver(&t.values));
-                },
-                _=> println!("{color_red}nothing")
+/// A formula compiled once into instructions, so evaluating it again does not walk the AST.
+/// Printing it disassembles it, one numbered instruction per line, with LAMBDA bodies indented.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<Op>,
+}
+
+impl Program {
+    fn disassemble(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        for (i, op) in self.code.iter().enumerate() {
+            writeln!(f, "{:indent$}{:04} {}", "", i, op, indent = indent)?;
+            if let Op::Lambda(lambda) = op {
+                lambda.body.disassemble(f, indent + 4)?;
             }
         }
-        return Box::new(Some(Response::Spreadsheet(Box::new(SpreadsheetResponseNode {values: sheets}))))
+        Ok(())
     }
+}
 
-    pub fn sheet_resolver(&self, node:&[Node])-> Box<Option<Response>> {
-        let mut sheets:Vec<Box<Option<Response>>> = Vec::new();
-        for t in node {
-            match t {
-                Node::Sheet(t) => {
-                    sheets.push(Box::new(Some(Response::Sheet(Box::new(ResultsSheet {id: t.id.literal.clone(), values: self.resolve(&t.values)})))));
-                },
-                _=> println!("{color_red}nothing")
-            }
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.disassemble(f, 0)
+    }
+}
+
+/// Compiles formulas into `Program`s. It knows the workbook's names, since calling one
+/// calls its LAMBDA even when it is spelled IF, AND or OR, and which of IF, AND and OR
+/// are still the built-in ones, since only those can skip evaluating arguments.
+#[derive(Debug, Default)]
+pub struct Compiler {
+    names: HashSet<String>,
+    short_circuits: HashSet<String>,
+}
+
+impl Compiler {
+    pub fn new(names: &[NameNode], functions: &Registry) -> Self {
+        Compiler {
+            names: names.iter().map(|n| n.token.literal.to_ascii_uppercase()).collect(),
+            short_circuits: ["IF", "AND", "OR"].into_iter().filter(|n| functions.is_builtin(n)).map(String::from).collect(),
         }
-        return Box::new(Some(Response::Spreadsheet(Box::new(SpreadsheetResponseNode {values: sheets}))))
     }
 
-    pub fn resolve(&self, node:&[Node]) -> Box<Option<Response>>{
-        let regex = Regex::new(r"[a-z][0-9]+").unwrap();
-        for t in node {
-            match t {
-                Node::DataCells(t) => {
-                    let a = self.interpreter(&t.values);
-                    let mut mem1:Vec<String> = Vec::new();
-                    let mut val1:Vec<String> = Vec::new();
-                    for mut i in a {
-                        mem1.append(&mut i.mem);
-                        val1.append(&mut i.val);
-                    }
-                    let mut interp = Interpreter::new(mem1.clone(), val1.clone());
-                    let mut re = interp.exec(mem1.clone(), val1.clone());
-                    
-                    let mut index = 0;
-                    while index < re.len() {
-                        let x = re.get(index).unwrap();
-                        if x == "0000" {
-                            re.remove(index);
-                            index-=1;
-                        }
-                        index+=1;
-                    }
-                    index = 0;
-                    while index < mem1.len() {
-                        let x = mem1.get(index).unwrap();
-                        if regex.captures(x).is_some() || x =="END_SUM" || x == "END_MUL"|| x == "END_DIV"|| x == "END_OR"|| x == "END_EQ"|| x == "END_CONCAT"|| x == "END_NOT"|| x == "END_GT" || x == "END_IF"|| x == "END_AND"{
-                            mem1.remove(index);
-                            index-=1;
+    pub fn compile(&self, node: &Node) -> Program {
+        let mut code: Vec<Op> = Vec::new();
+        self.emit(node, &mut Vec::new(), &mut code);
+        Program { code }
+    }
+
+    /// Appends the instructions that leave the value of `node` on the stack. `scope` holds the
+    /// names of the LET variables and LAMBDA parameters in scope, by slot.
+    fn emit(&self, node: &Node, scope: &mut Vec<String>, code: &mut Vec<Op>) {
+        let sheet = |t: &Option<Token>| t.as_ref().map(|t| t.literal.clone());
+        match node {
+            Node::IntLit(_) | Node::FloatLit(_) | Node::BoolLit(_) | Node::StringLit(_) => code.push(Op::Push(literal(node))),
+            Node::Notation(t) => code.push(Op::Load(sheet(&t.sheet), t.reference)),
+            Node::SpillRef(t) => code.push(Op::LoadSpill(sheet(&t.sheet), t.reference)),
+            Node::Range(t) => code.push(Op::LoadRange(sheet(&t.sheet), t.from, t.to)),
+            Node::Identifier(t) => code.push(variable(&t.token.literal, scope)),
+            Node::Invalid(t) => code.push(Op::Push(Value::error_with(ErrorKind::Value, &t.message))),
+            Node::Call(t) => {
+                let name = &t.name.literal;
+                if slot(name, scope).is_some() || self.names.contains(&name.to_ascii_uppercase()) {
+                    code.push(variable(name, scope));
+                    self.emit_all(&t.args, scope, code);
+                    code.push(Op::Apply(t.args.len()));
+                } else if !self.short_circuit(name, &t.args, scope, code) {
+                    self.emit_all(&t.args, scope, code);
+                    code.push(Op::Call(name.clone(), t.args.len()));
+                }
+            },
+            Node::Binary(t) => {
+                self.emit(&t.lhs, scope, code);
+                self.emit(&t.rhs, scope, code);
+                code.push(Op::Operator(t.token.literal.clone()));
+            },
+            Node::OperationLet(t) => {
+                let outer = scope.len();
+                for (name, value) in t.names.iter().zip(t.values.iter()) {
+                    self.emit(value, scope, code);
+                    code.push(Op::Bind);
+                    scope.push(name.literal.clone());
+                }
+                self.emit(&t.body, scope, code);
+                code.push(Op::Unbind(scope.len() - outer));
+                scope.truncate(outer);
+            },
+            Node::OperationLambda(t) => {
+                let params: Vec<String> = t.params.iter().map(|p| p.literal.clone()).collect();
+                let mut inner = scope.clone();
+                inner.extend(params.iter().cloned());
+                let mut body: Vec<Op> = Vec::new();
+                self.emit(&t.body, &mut inner, &mut body);
+                code.push(Op::Lambda(Arc::new(Lambda { params, body: Program { code: body } })));
+            },
+            Node::Apply(t) => {
+                self.emit(&t.callee, scope, code);
+                self.emit_all(&t.values, scope, code);
+                code.push(Op::Apply(t.values.len()));
+            },
+            _ => code.push(Op::Push(Value::error(ErrorKind::Value))),
+        }
+    }
+
+    fn emit_all(&self, nodes: &[Node], scope: &mut Vec<String>, code: &mut Vec<Op>) {
+        for node in nodes {
+            self.emit(node, scope, code);
+        }
+    }
+
+    /// IF, AND and OR compile to jumps rather than a call: IF only runs the branch it picks,
+    /// and AND/OR stop at the first argument that decides the result. `false` for other names,
+    /// and for these when the registry has replaced or left out the built-in.
+    fn short_circuit(&self, name: &str, args: &[Node], scope: &mut Vec<String>, code: &mut Vec<Op>) -> bool {
+        let name = name.to_ascii_uppercase();
+        if !self.short_circuits.contains(&name) {
+            return false;
+        }
+        let wrong = Value::error_with(ErrorKind::Value, "wrong number of arguments");
+        match name.as_str() {
+            "IF" => {
+                let [cond, then, otherwise] = args else {
+                    code.push(Op::Push(wrong));
+                    return true;
+                };
+                self.emit(cond, scope, code);
+                let test = code.len();
+                code.push(Op::Test(0));
+                code.push(Op::JumpIfFalse(0));
+                self.emit(then, scope, code);
+                let skip = code.len();
+                code.push(Op::Jump(0));
+                code[test + 1] = Op::JumpIfFalse(code.len());
+                self.emit(otherwise, scope, code);
+                code[test] = Op::Test(code.len());
+                code[skip] = Op::Jump(code.len());
+            },
+            "AND" | "OR" => {
+                if args.is_empty() {
+                    code.push(Op::Push(wrong));
+                    return true;
+                }
+                let decisive = name == "OR";
+                let mut exits: Vec<usize> = Vec::new();
+                for arg in args {
+                    self.emit(arg, scope, code);
+                    exits.push(code.len());
+                    code.push(Op::Decide(decisive, 0));
+                }
+                code.push(Op::Push(Value::Bool(!decisive)));
+                for exit in exits {
+                    code[exit] = Op::Decide(decisive, code.len());
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// The slot of the innermost LET variable or LAMBDA parameter called `name`.
+fn slot(name: &str, scope: &[String]) -> Option<usize> {
+    scope.iter().rposition(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// Loads a LET variable or LAMBDA parameter, or else the workbook name.
+fn variable(name: &str, scope: &[String]) -> Op {
+    match slot(name, scope) {
+        Some(slot) => Op::Local(slot),
+        None => Op::Name(name.to_string()),
+    }
+}
+
+/// How many arguments a function takes; a `max` of `None` means no upper limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// How a value of one type is converted where a function expects another. In both modes
+/// a blank cell, which holds empty text, is 0 where a number is expected, and aggregates
+/// like SUM and AND skip blanks inside ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Coercion {
+    /// Numbers and booleans are never converted; a value of the wrong type is `#VALUE!`.
+    #[default]
+    Strict,
+    /// Excel rules: TRUE counts as 1, numeric text such as "3" is 3 and a blank is 0.
+    /// Text and blanks inside ranges are skipped by aggregates like SUM and AND.
+    Lenient,
+}
+
+impl Coercion {
+    pub fn number(&self, v: &Value) -> Option<f64> {
+        match (self, v) {
+            (_, Value::Int(n)) => Some(*n as f64),
+            (_, Value::Number(n)) => Some(*n),
+            (_, v) if blank(v) => Some(0.0),
+            (Coercion::Lenient, Value::Bool(b)) => Some(if *b { 1.0 } else { 0.0 }),
+            (Coercion::Lenient, Value::Text(s)) if s.trim().is_empty() => Some(0.0),
+            (Coercion::Lenient, Value::Text(s)) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn boolean(&self, v: &Value) -> Option<bool> {
+        match (self, v) {
+            (_, Value::Bool(b)) => Some(*b),
+            (Coercion::Lenient, Value::Int(n)) => Some(*n != 0),
+            (Coercion::Lenient, Value::Number(n)) => Some(*n != 0.0),
+            (Coercion::Lenient, Value::Text(s)) if s.eq_ignore_ascii_case("true") => Some(true),
+            (Coercion::Lenient, Value::Text(s)) if s.eq_ignore_ascii_case("false") => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Every scalar has a text form, so this converts in both modes.
+    pub fn text(&self, v: &Value) -> Option<String> {
+        match v {
+            Value::Text(s) => Some(s.clone()),
+            Value::Int(_) | Value::Number(_) | Value::Bool(_) => Some(v.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// The arithmetic behind SUM, MULTIPLY, DIVIDE, subtraction and comparisons.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Numeric {
+    /// Plain `f64` arithmetic, so `SUM(0.1, 0.2)` is `0.30000000000000004`.
+    #[default]
+    Float,
+    /// Decimal arithmetic exact to `scale` digits after the point, so `SUM(0.1, 0.2)` is `0.3`.
+    /// Each operand is first rounded to that scale; results too large for it fall back to `f64`.
+    Decimal { scale: u32 },
+}
+
+impl Numeric {
+    pub fn sum(&self, a: &[f64]) -> f64 {
+        let sum = |scale| {
+            let u = a.iter().try_fold(0i128, |acc, x| acc.checked_add(units(*x, scale)?))?;
+            Some(from_units(u, scale))
+        };
+        self.decimal(sum).unwrap_or_else(|| a.iter().sum())
+    }
+
+    pub fn difference(&self, a: f64, b: f64) -> f64 {
+        let difference = |scale| Some(from_units(units(a, scale)?.checked_sub(units(b, scale)?)?, scale));
+        self.decimal(difference).unwrap_or(a - b)
+    }
+
+    pub fn product(&self, a: &[f64]) -> f64 {
+        let product = |scale| {
+            let one = 10i128.checked_pow(scale)?;
+            let u = a.iter().try_fold(one, |acc, x| Some(div_round(acc.checked_mul(units(*x, scale)?)?, one)))?;
+            Some(from_units(u, scale))
+        };
+        self.decimal(product).unwrap_or_else(|| a.iter().product())
+    }
+
+    /// `a / b`; the caller reports division by zero before getting here.
+    pub fn quotient(&self, a: f64, b: f64) -> f64 {
+        let quotient = |scale| {
+            let divisor = units(b, scale).filter(|d| *d != 0)?;
+            let dividend = units(a, scale)?.checked_mul(10i128.checked_pow(scale)?)?;
+            Some(from_units(div_round(dividend, divisor), scale))
+        };
+        self.decimal(quotient).unwrap_or(a / b)
+    }
+
+    pub fn compare(&self, a: f64, b: f64) -> Ordering {
+        let compare = |scale| Some(units(a, scale)?.cmp(&units(b, scale)?));
+        self.decimal(compare).unwrap_or_else(|| a.partial_cmp(&b).unwrap_or(Ordering::Equal))
+    }
+
+    /// Runs `op` at the decimal scale; `None` in float mode or when `op` overflows.
+    fn decimal<T>(&self, op: impl Fn(u32) -> Option<T>) -> Option<T> {
+        match *self {
+            Numeric::Float => None,
+            Numeric::Decimal { scale } => op(scale),
+        }
+    }
+}
+
+/// `x` as a whole number of `10^-scale` units, rounded half away from zero.
+/// Works from the shortest decimal form of `x`, so `0.1` is exactly one tenth.
+fn units(x: f64, scale: u32) -> Option<i128> {
+    if !x.is_finite() {
+        return None;
+    }
+    let text = format!("{:e}", x.abs());
+    let (mantissa, exp) = text.split_once('e')?;
+    let (whole, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: i128 = format!("{}{}", whole, frac).parse().ok()?;
+    let shift = exp.parse::<i64>().ok()? - frac.len() as i64 + scale as i64;
+    let u = if shift >= 0 {
+        digits.checked_mul(10i128.checked_pow(u32::try_from(shift).ok()?)?)?
+    } else {
+        match 10i128.checked_pow(u32::try_from(-shift).ok()?) {
+            Some(p) => div_round(digits, p),
+            None => 0,
+        }
+    };
+    Some(if x < 0.0 { -u } else { u })
+}
+
+fn from_units(units: i128, scale: u32) -> f64 {
+    format!("{}e-{}", units, scale).parse().unwrap_or(f64::NAN)
+}
+
+/// `n / d` rounded half away from zero.
+fn div_round(n: i128, d: i128) -> i128 {
+    let q = n / d;
+    let r = n % d;
+    if r.abs() * 2 >= d.abs() {
+        q + n.signum() * d.signum()
+    } else {
+        q
+    }
+}
+
+/// Options that change how formulas evaluate, chosen once per run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Settings {
+    pub coercion: Coercion,
+    pub numeric: Numeric,
+}
+
+/// What a parameter accepts; only literal arguments can be checked before evaluation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Any,
+    Number,
+    Bool,
+    Text,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Any => write!(f, "any value"),
+            Kind::Number => write!(f, "a number"),
+            Kind::Bool => write!(f, "a boolean"),
+            Kind::Text => write!(f, "text"),
+        }
+    }
+}
+
+impl Arity {
+    pub fn exactly(n: usize) -> Self {
+        Arity { min: n, max: Some(n) }
+    }
+
+    pub fn range(min: usize, max: usize) -> Self {
+        Arity { min, max: Some(max) }
+    }
+
+    pub fn at_least(min: usize) -> Self {
+        Arity { min, max: None }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+/// A function that formulas can call by name. Arguments arrive already
+/// evaluated, and an error argument is returned before `evaluate` is reached.
+pub trait Function: Send + Sync {
+    fn name(&self) -> &str;
+    fn arity(&self) -> Arity;
+
+    /// Kind of each parameter; the last one repeats for variadic functions.
+    fn params(&self) -> &[Kind] {
+        &[]
+    }
+
+    fn evaluate(&self, args: &[Value], settings: &Settings) -> Value;
+}
+
+/// One of the functions shipped with the evaluator.
+struct Builtin {
+    name: &'static str,
+    arity: Arity,
+    params: &'static [Kind],
+}
+
+impl Function for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn params(&self) -> &[Kind] {
+        self.params
+    }
+
+    fn evaluate(&self, args: &[Value], settings: &Settings) -> Value {
+        builtin(self.name, args.to_vec(), settings)
+    }
+}
+
+/// The functions the evaluator dispatches calls through, keyed by upper-case name.
+pub struct Registry {
+    functions: HashMap<String, Arc<dyn Function>>,
+    /// Names still bound to the function shipped under them.
+    builtins: HashSet<String>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    /// A registry holding every built-in function.
+    pub fn new() -> Self {
+        let mut registry = Registry::empty();
+        let builtins: [(&'static str, Arity, &'static [Kind]); 26] = [
+            ("SUM", Arity::at_least(1), &[Kind::Number]),
+            ("MULTIPLY", Arity::at_least(1), &[Kind::Number]),
+            ("DIVIDE", Arity::exactly(2), &[Kind::Number]),
+            ("EQ", Arity::exactly(2), &[Kind::Any]),
+            ("NE", Arity::exactly(2), &[Kind::Any]),
+            ("GT", Arity::exactly(2), &[Kind::Any]),
+            ("LT", Arity::exactly(2), &[Kind::Any]),
+            ("GTE", Arity::exactly(2), &[Kind::Any]),
+            ("LTE", Arity::exactly(2), &[Kind::Any]),
+            ("NOT", Arity::exactly(1), &[Kind::Bool]),
+            ("AND", Arity::at_least(1), &[Kind::Bool]),
+            ("OR", Arity::at_least(1), &[Kind::Bool]),
+            ("IF", Arity::exactly(3), &[Kind::Bool, Kind::Any]),
+            ("CONCAT", Arity::at_least(1), &[Kind::Any]),
+            ("PMT", Arity::range(3, 5), &[Kind::Number]),
+            ("PV", Arity::range(3, 5), &[Kind::Number]),
+            ("FV", Arity::range(3, 5), &[Kind::Number]),
+            ("NPV", Arity::at_least(2), &[Kind::Number]),
+            ("IRR", Arity::range(1, 2), &[Kind::Any, Kind::Number]),
+            ("RATE", Arity::range(3, 6), &[Kind::Number]),
+            ("NPER", Arity::range(3, 5), &[Kind::Number]),
+            ("SEQUENCE", Arity::range(1, 4), &[Kind::Number]),
+            ("FILTER", Arity::range(2, 3), &[Kind::Any]),
+            ("SORT", Arity::range(1, 4), &[Kind::Any, Kind::Number, Kind::Number, Kind::Bool]),
+            ("UNIQUE", Arity::range(1, 3), &[Kind::Any, Kind::Bool]),
+            ("TRANSPOSE", Arity::exactly(1), &[Kind::Any]),
+        ];
+        for (name, arity, params) in builtins {
+            registry.register(Builtin { name, arity, params });
+            registry.builtins.insert(name.to_string());
+        }
+        registry
+    }
+
+    /// A registry with no functions at all.
+    pub fn empty() -> Self {
+        Registry { functions: HashMap::new(), builtins: HashSet::new() }
+    }
+
+    /// Adds a function, replacing any existing one with the same name.
+    pub fn register(&mut self, function: impl Function + 'static) {
+        let name = function.name().to_ascii_uppercase();
+        self.builtins.remove(&name);
+        self.functions.insert(name, Arc::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Function> {
+        self.functions.get(&name.to_ascii_uppercase()).map(|f| f.as_ref())
+    }
+
+    /// Whether `name` calls the built-in function of that name.
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtins.contains(&name.to_ascii_uppercase())
+    }
+}
+
+/// Supplies the values of cells the payload does not hold, from a map, a file or a database.
+pub trait CellSource: Send + Sync {
+    /// The value at `cell` on sheet `sheet`, or `None` for an empty cell. A formula given to
+    /// `evaluate_formula` sits on a sheet with the empty id, which its unqualified references name.
+    fn value(&self, sheet: &str, cell: CellRef) -> Option<Value>;
+}
+
+impl fmt::Debug for dyn CellSource + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CellSource")
+    }
+}
+
+/// Cells keyed by address: `A1` on the formula's own sheet, `sheet-2!A1` elsewhere.
+impl CellSource for HashMap<String, Value> {
+    fn value(&self, sheet: &str, cell: CellRef) -> Option<Value> {
+        if sheet.is_empty() {
+            self.get(&cell.to_string()).cloned()
+        } else {
+            self.get(&format!("{}!{}", sheet, cell)).cloned()
+        }
+    }
+}
+
+/// The input rows of one sheet, as written in the payload.
+#[derive(Debug)]
+pub struct SheetRows<'a> {
+    pub id: String,
+    pub rows: Vec<Vec<&'a Cell>>,
+}
+
+/// A cell position: sheet index, then zero-based row and column.
+type Addr = (usize, usize, usize);
+
+#[derive(Debug)]
+pub struct Interpreter<'a> {
+    pub sheets: &'a [SheetRows<'a>],
+    pub names: &'a [NameNode],
+    pub functions: &'a Registry,
+    pub settings: Settings,
+    source: Option<&'a dyn CellSource>,
+    /// Sheets only the cell source knows about; their indices follow the payload's sheets.
+    external: Vec<String>,
+    results: HashMap<Addr, Value>,
+    pending: HashSet<Addr>,
+    pending_names: HashSet<usize>,
+    /// Cells covered by a spilled array, mapped to the formula cell that spilled into them.
+    spills: HashMap<Addr, Addr>,
+    /// Sheet that unqualified references in the formula being evaluated point into.
+    sheet: usize,
+    depth: usize,
+    dependencies: Dependencies,
+    /// Cells whose value is being worked out, innermost last; reads are recorded against the last one.
+    reading: Vec<Addr>,
+    /// Anchors whose spill lost a contested cell, to be evaluated again along with their readers.
+    invalidated: Vec<Addr>,
+    /// What earlier dependency levels worked out, shared read-only by the threads evaluating the next one.
+    base: Option<&'a Interpreter<'a>>,
+    /// Index of each workbook name, by upper-case name.
+    name_ids: Arc<HashMap<String, usize>>,
+    /// Index of each payload sheet, by lower-case id.
+    sheet_ids: Arc<HashMap<String, usize>>,
+    /// Per sheet, the formulas a blank cell may still have to wait for; built on first use.
+    unevaluated: HashMap<usize, Unevaluated>,
+    /// The values of the workbook names, compiled.
+    programs: Arc<Vec<Program>>,
+}
+
+/// Which cells each cell's value was computed from, recorded while evaluating.
+#[derive(Debug, Default)]
+struct Dependencies {
+    reads: HashMap<Addr, HashSet<Addr>>,
+    readers: HashMap<Addr, HashSet<Addr>>,
+}
+
+impl Dependencies {
+    fn record(&mut self, reader: Addr, target: Addr) {
+        self.reads.entry(reader).or_default().insert(target);
+        self.readers.entry(target).or_default().insert(reader);
+    }
+
+    /// Drops what `reader` read last time, before it is evaluated again.
+    fn forget(&mut self, reader: Addr) {
+        for target in self.reads.remove(&reader).unwrap_or_default() {
+            if let Some(readers) = self.readers.get_mut(&target) {
+                readers.remove(&reader);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Dependencies) {
+        if self.reads.is_empty() {
+            *self = other;
+            return;
+        }
+        for (reader, targets) in other.reads {
+            self.reads.entry(reader).or_default().extend(targets);
+        }
+        for (target, readers) in other.readers {
+            self.readers.entry(target).or_default().extend(readers);
+        }
+    }
+
+    /// `at` followed by every cell that read it, directly or through other cells.
+    fn affected(&self, at: Addr) -> Vec<Addr> {
+        let mut seen: HashSet<Addr> = HashSet::from([at]);
+        let mut res: Vec<Addr> = vec![at];
+        let mut i = 0;
+        while i < res.len() {
+            for reader in self.readers.get(&res[i]).into_iter().flatten() {
+                if seen.insert(*reader) {
+                    res.push(*reader);
+                }
+            }
+            i += 1;
+        }
+        res
+    }
+}
+
+/// The formula cells of one sheet that are neither evaluated nor being evaluated, indexed
+/// so a blank cell can find those above and to the left of it, any of which might spill there.
+#[derive(Debug, Default)]
+struct Unevaluated {
+    /// Rows of the waiting formulas, per column.
+    columns: Vec<BTreeSet<usize>>,
+    /// A segment tree over the columns, holding the waiting formula earliest in the sheet within each span.
+    tree: Vec<Option<(usize, usize)>>,
+}
+
+impl Unevaluated {
+    fn insert(&mut self, (row, col): (usize, usize)) {
+        if col >= self.columns.len() {
+            self.columns.resize_with(col + 1, BTreeSet::new);
+        }
+        if self.columns[col].insert(row) {
+            self.update(col);
+        }
+    }
+
+    fn remove(&mut self, (row, col): (usize, usize)) {
+        if self.columns.get_mut(col).is_some_and(|rows| rows.remove(&row)) {
+            self.update(col);
+        }
+    }
+
+    fn update(&mut self, col: usize) {
+        let size = self.tree.len() / 2;
+        if col >= size {
+            let size = self.columns.len().next_power_of_two();
+            self.tree = vec![None; 2 * size];
+            for (c, rows) in self.columns.iter().enumerate() {
+                self.tree[size + c] = rows.first().map(|r| (*r, c));
+            }
+            for i in (1..size).rev() {
+                self.tree[i] = earliest(self.tree[2 * i], self.tree[2 * i + 1]);
+            }
+            return;
+        }
+        let mut i = size + col;
+        self.tree[i] = self.columns[col].first().map(|r| (*r, col));
+        while i > 1 {
+            i /= 2;
+            self.tree[i] = earliest(self.tree[2 * i], self.tree[2 * i + 1]);
+        }
+    }
+
+    /// The waiting formula earliest in the sheet at or above `row` and at or left of `col`.
+    fn first(&self, row: usize, col: usize) -> Option<(usize, usize)> {
+        let size = self.tree.len() / 2;
+        let (mut lo, mut hi) = (size, size + (col + 1).min(size));
+        let mut res = None;
+        while lo < hi {
+            if lo % 2 == 1 {
+                res = earliest(res, self.tree[lo]);
+                lo += 1;
+            }
+            if hi % 2 == 1 {
+                hi -= 1;
+                res = earliest(res, self.tree[hi]);
+            }
+            lo /= 2;
+            hi /= 2;
+        }
+        res.filter(|(r, _)| *r <= row)
+    }
+}
+
+fn earliest(a: Option<(usize, usize)>, b: Option<(usize, usize)>) -> Option<(usize, usize)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Stands for where arrays spill on a sheet. Blank cells read it, and it changes whenever an
+/// array on the sheet changes shape, instead of every blank cell reading every formula that
+/// might spill into it.
+fn layout(sheet: usize) -> Addr {
+    (sheet, usize::MAX, usize::MAX)
+}
+
+/// Rows and columns of an array that spilled, `None` for any other value.
+fn footprint(v: Option<&Value>) -> Option<(usize, usize)> {
+    match v {
+        Some(Value::Array(a)) => Some((a.len(), a.first().map_or(0, |r| r.len()))),
+        _ => None,
+    }
+}
+
+/// What an `Interpreter` worked out, kept so a later one can resume from it
+/// instead of evaluating every cell again.
+#[derive(Debug, Default)]
+pub struct Calculation {
+    results: HashMap<Addr, Value>,
+    spills: HashMap<Addr, Addr>,
+    dependencies: Dependencies,
+    external: Vec<String>,
+    unevaluated: HashMap<usize, Unevaluated>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(sheets: &'a [SheetRows<'a>], names: &'a [NameNode], functions: &'a Registry, settings: Settings) -> Self {
+        let compiler = Compiler::new(names, functions);
+        Self {
+                sheets,
+                names,
+                functions,
+                settings,
+                source: None,
+                external: Vec::new(),
+                results: HashMap::new(),
+                pending: HashSet::new(),
+                pending_names: HashSet::new(),
+                spills: HashMap::new(),
+                sheet: 0,
+                depth: 0,
+                dependencies: Dependencies::default(),
+                reading: Vec::new(),
+                invalidated: Vec::new(),
+                base: None,
+                // Reversed, so the first of two entries with the same name or id wins.
+                name_ids: Arc::new(names.iter().enumerate().rev().map(|(i, n)| (n.token.literal.to_ascii_uppercase(), i)).collect()),
+                sheet_ids: Arc::new(sheets.iter().enumerate().rev().map(|(i, s)| (s.id.to_ascii_lowercase(), i)).collect()),
+                unevaluated: HashMap::new(),
+                programs: Arc::new(names.iter().map(|n| compiler.compile(&n.value)).collect()),
+            }
+    }
+
+    /// Reads cells the payload does not hold from `source`, instead of treating
+    /// references to unknown sheets as `#REF!`.
+    pub fn with_source(mut self, source: &'a dyn CellSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Evaluates a formula that sits on no sheet of the payload, its unqualified
+    /// references going to the cell source.
+    pub fn evaluate(&mut self, node: &Node) -> Value {
+        self.sheet = self.external_sheet("");
+        let program = Compiler::new(self.names, self.functions).compile(node);
+        self.run(&program, &mut Vec::new())
+    }
+
+    /// Picks up the results of an earlier calculation over the same cells.
+    pub fn resume(mut self, calculation: Calculation) -> Self {
+        self.results = calculation.results;
+        self.spills = calculation.spills;
+        self.dependencies = calculation.dependencies;
+        self.external = calculation.external;
+        self.unevaluated = calculation.unevaluated;
+        self
+    }
+
+    pub fn into_calculation(self) -> Calculation {
+        Calculation { results: self.results, spills: self.spills, dependencies: self.dependencies, external: self.external,
+            unevaluated: self.unevaluated }
+    }
+
+    pub fn sheet_index(&self, id: &str) -> Option<usize> {
+        self.sheet_ids.get(&id.to_ascii_lowercase()).copied()
+    }
+
+    fn name_index(&self, name: &str) -> Option<usize> {
+        self.name_ids.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    /// Evaluates `cells` and every cell that read them, then again every cell a contested
+    /// spill made stale meanwhile, until nothing is left to do. Returns the final value of
+    /// each cell evaluated.
+    fn settle(&mut self, mut cells: Vec<Addr>) -> HashMap<Addr, Value> {
+        let mut res: HashMap<Addr, Value> = HashMap::new();
+        // Spills that change which cells their rivals read can evict each other forever.
+        for _ in 0..MAX_SETTLE_ROUNDS {
+            if cells.is_empty() {
+                break;
+            }
+            let mut stale: Vec<Addr> = cells.iter().flat_map(|at| self.dependencies.affected(*at)).collect();
+            stale.retain(|at| *at != layout(at.0));
+            stale.sort();
+            stale.dedup();
+            let before: Vec<Option<(usize, usize)>> = stale.iter().map(|at| footprint(self.results.get(at))).collect();
+            self.clear(&stale);
+            let values = self.update(&stale);
+            cells = std::mem::take(&mut self.invalidated);
+            for (at, shape) in stale.iter().zip(before) {
+                if footprint(self.results.get(at)) != shape {
+                    cells.push(layout(at.0));
+                }
+            }
+            res.extend(stale.into_iter().zip(values));
+        }
+        res
+    }
+
+    /// Evaluates `cells` on an interpreter that has evaluated nothing yet, with the same result
+    /// as `settle`. Sheets whose formulas never refer to each other are evaluated on threads of
+    /// their own, and within them the formulas of each dependency level are shared out between
+    /// about `cpus` threads in all. Sheets whose formulas might read each other in a cycle are evaluated in order on
+    /// one thread, though a cycle through a blank cell that an array spills into may still be
+    /// reported at a different cell than `settle` would.
+    fn settle_parallel(&mut self, cells: Vec<Addr>, cpus: usize) -> HashMap<Addr, Value> {
+        // Finding the references also registers every sheet only the cell source knows,
+        // so all the threads agree on their indices.
+        let formulas: Vec<Addr> = cells.iter().copied().filter(|at| self.formula(*at)).collect();
+        let reads: HashMap<Addr, (Vec<Addr>, Vec<usize>)> = formulas.into_iter().map(|at| (at, self.references(at))).collect();
+
+        // Sheets are grouped with every sheet their formulas refer to.
+        let mut group: Vec<usize> = (0..self.sheets.len()).collect();
+        let links: HashSet<(usize, usize)> = reads.iter()
+            .flat_map(|(at, (targets, blank))| targets.iter().map(|target| target.0).chain(blank.iter().copied()).map(move |s| (at.0, s)))
+            .collect();
+        for (a, b) in links {
+            let (keep, merged) = (group[a].min(group[b]), group[a].max(group[b]));
+            group.iter_mut().filter(|g| **g == merged).for_each(|g| *g = keep);
+        }
+        let mut groups = group.clone();
+        groups.sort();
+        groups.dedup();
+        let threads = cpus.div_ceil(groups.len().max(1));
+
+        let done: Vec<(HashMap<Addr, Value>, Calculation)> = thread::scope(|scope| {
+            let workers: Vec<_> = groups.iter().map(|g| {
+                let cells: Vec<Addr> = cells.iter().copied().filter(|at| group.get(at.0) == Some(g)).collect();
+                let mut interp = self.fork();
+                let reads = &reads;
+                scope.spawn(move || {
+                    let values = interp.settle_group(cells, reads, threads);
+                    (values, interp.into_calculation())
+                })
+            }).collect();
+            workers.into_iter().map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e))).collect()
+        });
+        let mut res: HashMap<Addr, Value> = HashMap::new();
+        for (values, calculation) in done {
+            res.extend(values);
+            self.merge(calculation);
+        }
+        res
+    }
+
+    /// Evaluates the cells of one group of sheets, a dependency level at a time when its
+    /// formulas cannot read each other in a cycle.
+    fn settle_group(&mut self, cells: Vec<Addr>, reads: &HashMap<Addr, (Vec<Addr>, Vec<usize>)>, threads: usize) -> HashMap<Addr, Value> {
+        for level in levels(&cells, reads).unwrap_or_default() {
+            let chunk = level.len().div_ceil(threads).max(MIN_LEVEL_CHUNK);
+            if level.len() <= chunk {
+                for at in level {
+                    self.raw(at);
+                }
+                continue;
+            }
+            let base = &*self;
+            let outcomes: Vec<(Calculation, Vec<Addr>)> = thread::scope(|scope| {
+                let workers: Vec<_> = level.chunks(chunk).map(|part| scope.spawn(move || base.work(part))).collect();
+                workers.into_iter().map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e))).collect()
+            });
+            for (calculation, invalidated) in outcomes {
+                self.merge(calculation);
+                self.invalidated.extend(invalidated);
+            }
+        }
+        let values = self.update(&cells);
+        let mut res: HashMap<Addr, Value> = cells.into_iter().zip(values).collect();
+        let invalidated = std::mem::take(&mut self.invalidated);
+        res.extend(self.settle(invalidated));
+        res
+    }
+
+    /// A fresh interpreter over the same payload, knowing the same sheets of the cell source.
+    /// The compiled names and the indices of names and sheets are shared, not built again.
+    fn fork(&self) -> Interpreter<'a> {
+        Interpreter {
+            sheets: self.sheets,
+            names: self.names,
+            functions: self.functions,
+            settings: self.settings,
+            source: self.source,
+            external: self.external.clone(),
+            results: HashMap::new(),
+            pending: HashSet::new(),
+            pending_names: HashSet::new(),
+            spills: HashMap::new(),
+            sheet: 0,
+            depth: 0,
+            dependencies: Dependencies::default(),
+            reading: Vec::new(),
+            invalidated: Vec::new(),
+            base: None,
+            name_ids: self.name_ids.clone(),
+            sheet_ids: self.sheet_ids.clone(),
+            unevaluated: HashMap::new(),
+            programs: self.programs.clone(),
+        }
+    }
+
+    /// Evaluates the formulas in `cells` on top of what this interpreter knows. Returns only
+    /// what that added, and the anchors a contested spill evicted.
+    fn work(&self, cells: &[Addr]) -> (Calculation, Vec<Addr>) {
+        let mut worker = self.fork();
+        worker.base = Some(self);
+        for at in cells {
+            worker.raw(*at);
+        }
+        let invalidated = std::mem::take(&mut worker.invalidated);
+        (worker.into_calculation(), invalidated)
+    }
+
+    /// Takes in what another interpreter evaluated. Of two anchors spilling into the same cell,
+    /// the one earliest in the sheet keeps it and the other is evaluated again, as in `spill`.
+    fn merge(&mut self, calculation: Calculation) {
+        if self.results.is_empty() {
+            self.results = calculation.results;
+        } else {
+            for (at, v) in calculation.results {
+                self.results.entry(at).or_insert(v);
+            }
+        }
+        for (target, anchor) in calculation.spills {
+            match self.spills.get(&target).copied() {
+                Some(other) if other != anchor => {
+                    self.invalidated.push(other.max(anchor));
+                    self.spills.insert(target, other.min(anchor));
+                },
+                _ => {
+                    self.spills.insert(target, anchor);
+                },
+            }
+        }
+        self.dependencies.merge(calculation.dependencies);
+    }
+
+    /// The formula cells the formula at `at` might read, found without evaluating it, directly
+    /// or through names; and the sheets where it reads a blank cell, which a spilled array may cover.
+    fn references(&mut self, at: Addr) -> (Vec<Addr>, Vec<usize>) {
+        let Some(node) = self.node(at) else { return (Vec::new(), Vec::new()) };
+        self.sheet = at.0;
+        let mut cells: Vec<(Addr, bool)> = Vec::new();
+        self.refers(node, &mut HashSet::new(), &mut cells);
+        let mut res: Vec<Addr> = Vec::new();
+        let mut blank: Vec<usize> = Vec::new();
+        for (target, scalar) in cells {
+            if self.formula(target) {
+                res.push(target);
+            } else if scalar && self.blank(target) && !blank.contains(&target.0) {
+                blank.push(target.0);
+            }
+        }
+        res.sort();
+        res.dedup();
+        (res, blank)
+    }
+
+    /// Collects the payload cells `node` refers to, flagging those read as a single cell,
+    /// where a spilled array may show.
+    fn refers(&mut self, node: &Node, names: &mut HashSet<usize>, cells: &mut Vec<(Addr, bool)>) {
+        match node {
+            Node::Notation(t) | Node::SpillRef(t) => {
+                if let Some(sheet) = self.target(t.sheet.as_ref().map(|t| t.literal.as_str())).filter(|s| *s < self.sheets.len()) {
+                    cells.push(((sheet, t.reference.row, t.reference.col), matches!(node, Node::Notation(_))));
+                }
+            },
+            Node::Range(t) => {
+                if let Some(sheet) = self.target(t.sheet.as_ref().map(|t| t.literal.as_str())).filter(|s| *s < self.sheets.len()) {
+                    for row in t.from.row.min(t.to.row)..=t.from.row.max(t.to.row) {
+                        for col in t.from.col.min(t.to.col)..=t.from.col.max(t.to.col) {
+                            cells.push(((sheet, row, col), true));
                         }
-                        index+=1;
                     }
-                    return Box::new(Some(Response::DataCells(Box::new(ResultsData {data: "data".to_string(), cells: re}))))
+                }
+            },
+            Node::Identifier(t) => self.refers_name(&t.token.literal, names, cells),
+            Node::Call(t) => {
+                self.refers_name(&t.name.literal, names, cells);
+                for arg in t.args.iter() {
+                    self.refers(arg, names, cells);
+                }
+            },
+            Node::Binary(t) => {
+                self.refers(&t.lhs, names, cells);
+                self.refers(&t.rhs, names, cells);
+            },
+            Node::OperationLet(t) => {
+                for value in t.values.iter() {
+                    self.refers(value, names, cells);
+                }
+                self.refers(&t.body, names, cells);
+            },
+            Node::OperationLambda(t) => self.refers(&t.body, names, cells),
+            Node::Apply(t) => {
+                self.refers(&t.callee, names, cells);
+                for value in t.values.iter() {
+                    self.refers(value, names, cells);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn refers_name(&mut self, name: &str, names: &mut HashSet<usize>, cells: &mut Vec<(Addr, bool)>) {
+        let all = self.names;
+        if let Some(index) = self.name_index(name) {
+            if names.insert(index) {
+                self.refers(&all[index].value, names, cells);
+            }
+        }
+    }
+
+    /// The values of the given cells. Formulas are all evaluated before any blank cell
+    /// is looked up, so every array that could spill into it already has.
+    fn update(&mut self, cells: &[Addr]) -> Vec<Value> {
+        for at in cells {
+            self.raw(*at);
+        }
+        cells.iter().map(|at| {
+            self.reading.push(*at);
+            let v = self.cell(*at);
+            self.reading.pop();
+            v
+        }).collect()
+    }
+
+    /// Forgets what is known about `cells`, so they are evaluated again.
+    fn clear(&mut self, cells: &[Addr]) {
+        for at in cells {
+            self.results.remove(at);
+            self.dependencies.forget(*at);
+            let formula = self.formula(*at);
+            if let Some(unevaluated) = self.unevaluated.get_mut(&at.0) {
+                if formula {
+                    unevaluated.insert((at.1, at.2));
+                } else {
+                    unevaluated.remove((at.1, at.2));
+                }
+            }
+        }
+        let cleared: HashSet<Addr> = cells.iter().copied().collect();
+        self.spills.retain(|_, anchor| !cleared.contains(anchor));
+    }
+
+    fn read(&mut self, target: Addr) {
+        if let Some(reader) = self.reading.last() {
+            if *reader != target {
+                self.dependencies.record(*reader, target);
+            }
+        }
+    }
+
+    fn result(&self, at: Addr) -> Option<&Value> {
+        self.results.get(&at).or_else(|| self.base?.results.get(&at))
+    }
+
+    /// The formula cell whose array covers `at`, if any.
+    fn spill_anchor(&self, at: Addr) -> Option<Addr> {
+        self.spills.get(&at).or_else(|| self.base?.spills.get(&at)).copied()
+    }
+
+    fn node(&self, at: Addr) -> Option<&'a Node> {
+        self.input(at).map(|c| &c.node)
+    }
+
+    fn input(&self, (sheet, row, col): Addr) -> Option<&'a Cell> {
+        self.sheets.get(sheet).and_then(|s| s.rows.get(row)).and_then(|r| r.get(col)).copied()
+    }
+
+    /// Empty input cells, which spilled arrays are allowed to cover.
+    fn blank(&self, at: Addr) -> bool {
+        match self.node(at) {
+            None => true,
+            Some(Node::StringLit(t)) => t.token.literal.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Cells holding a formula rather than a literal, the only ones that can spill.
+    fn formula(&self, at: Addr) -> bool {
+        !matches!(self.node(at), None | Some(Node::IntLit(_)) | Some(Node::FloatLit(_))
+            | Some(Node::BoolLit(_)) | Some(Node::StringLit(_)))
+    }
+
+    /// The value of the cell as a single scalar, taking spilled arrays into account.
+    fn cell(&mut self, at: Addr) -> Value {
+        if at.0 >= self.sheets.len() {
+            return self.external(at);
+        }
+        match self.raw(at) {
+            Some(Value::Array(a)) => a[0][0].clone(),
+            Some(v) => v,
+            None => self.spilled(at).or_else(|| self.outside(at)).unwrap_or(Value::Text("".to_string())),
+        }
+    }
+
+    /// A cell past the end of its payload sheet's rows, which the cell source may know.
+    fn outside(&mut self, (sheet, row, col): Addr) -> Option<Value> {
+        if self.node((sheet, row, col)).is_some() {
+            return None;
+        }
+        let cell = CellRef { row, col, row_abs: false, col_abs: false };
+        self.source?.value(&self.sheets[sheet].id, cell)
+    }
+
+    /// The result of the formula written in the cell, or `None` for a blank cell.
+    fn raw(&mut self, at: Addr) -> Option<Value> {
+        self.read(at);
+        if let Some(v) = self.result(at) {
+            return Some(v.clone());
+        }
+        if self.blank(at) {
+            return None;
+        }
+        let program = &self.input(at)?.program;
+        if let Some(unevaluated) = self.unevaluated.get_mut(&at.0) {
+            unevaluated.remove((at.1, at.2));
+        }
+        if !self.pending.insert(at) {
+            return Some(Value::error_with(ErrorKind::Ref, "circular reference"));
+        }
+        let outer = self.sheet;
+        self.sheet = at.0;
+        self.reading.push(at);
+        let mut v = self.run(program, &mut Vec::new());
+        self.sheet = outer;
+        self.pending.remove(&at);
+        if let Value::Array(a) = &v {
+            v = self.spill(at, a.clone());
+        }
+        self.reading.pop();
+        self.results.insert(at, v.clone());
+        Some(v)
+    }
+
+    fn spill(&mut self, anchor: Addr, a: Arc<Vec<Vec<Value>>>) -> Value {
+        if a.is_empty() || a[0].is_empty() {
+            return Value::error(ErrorKind::Calc);
+        }
+        let mut targets:Vec<Addr> = Vec::new();
+        let mut evicted:Vec<Addr> = Vec::new();
+        for (i, r) in a.iter().enumerate() {
+            for j in 0..r.len() {
+                let target = (anchor.0, anchor.1 + i, anchor.2 + j);
+                if target == anchor {
+                    continue;
+                }
+                // Only a cell in the way is read: the value of a blank one changes with every
+                // spill over it, and the anchor would be evaluated again each time. Writing into
+                // a blank target is caught by `Workbook::set_cell` instead.
+                if !self.blank(target) {
+                    self.read(target);
+                    return Value::error(ErrorKind::Spill);
+                }
+                // The anchor earliest in the sheet wins a contested cell, whichever was evaluated first.
+                match self.spill_anchor(target) {
+                    Some(other) if other < anchor => {
+                        self.read(other);
+                        return Value::error(ErrorKind::Spill);
+                    },
+                    Some(other) => evicted.push(other),
+                    None => {},
+                }
+                targets.push(target);
+            }
+        }
+        self.invalidated.extend(evicted);
+        for target in targets {
+            self.spills.insert(target, anchor);
+        }
+        Value::Array(a)
+    }
+
+    /// The spilled value covering a blank cell. Formulas above and to the left
+    /// are evaluated first, since any of them may spill this far.
+    fn spilled(&mut self, at: Addr) -> Option<Value> {
+        let (sheet, row, col) = at;
+        self.read(layout(sheet));
+        if self.spill_anchor(at).is_none() {
+            if !self.unevaluated.contains_key(&sheet) {
+                let unevaluated = self.waiting(sheet);
+                self.unevaluated.insert(sheet, unevaluated);
+            }
+            while let Some((r, c)) = self.unevaluated[&sheet].first(row, col) {
+                self.raw((sheet, r, c));
+            }
+        }
+        let (_, ar, ac) = self.spill_anchor(at)?;
+        self.read((sheet, ar, ac));
+        match self.result((sheet, ar, ac)) {
+            Some(Value::Array(a)) => a.get(row - ar).and_then(|r| r.get(col - ac)).cloned(),
+            _ => None,
+        }
+    }
+
+    /// The formulas of a sheet that have no value yet and are not being evaluated.
+    fn waiting(&self, sheet: usize) -> Unevaluated {
+        let mut unevaluated = Unevaluated::default();
+        for (r, row) in self.sheets[sheet].rows.iter().enumerate() {
+            for c in 0..row.len() {
+                let at = (sheet, r, c);
+                if self.formula(at) && self.result(at).is_none() && !self.pending.contains(&at) {
+                    unevaluated.insert((r, c));
+                }
+            }
+        }
+        unevaluated
+    }
+
+    fn range(&mut self, sheet: usize, from: (usize, usize), to: (usize, usize)) -> Value {
+        let mut rows:Vec<Vec<Value>> = Vec::new();
+        for row in from.0.min(to.0)..=from.0.max(to.0) {
+            let mut r:Vec<Value> = Vec::new();
+            for col in from.1.min(to.1)..=from.1.max(to.1) {
+                r.push(self.cell((sheet, row, col)));
+            }
+            rows.push(r);
+        }
+        Value::Array(Arc::new(rows))
+    }
+
+    /// The sheet a reference points into: its `sheet!` qualifier, or the current sheet.
+    fn target(&mut self, sheet: Option<&str>) -> Option<usize> {
+        match sheet {
+            Some(id) => match self.sheet_index(id) {
+                Some(index) => Some(index),
+                None if self.source.is_some() => Some(self.external_sheet(id)),
+                None => None,
+            },
+            None => Some(self.sheet),
+        }
+    }
+
+    fn external_sheet(&mut self, id: &str) -> usize {
+        let index = match self.external.iter().position(|s| s.eq_ignore_ascii_case(id)) {
+            Some(index) => index,
+            None => {
+                self.external.push(id.to_string());
+                self.external.len() - 1
+            },
+        };
+        self.sheets.len() + index
+    }
+
+    /// A cell on a sheet only the cell source knows about; blank when it has no value there.
+    fn external(&mut self, (sheet, row, col): Addr) -> Value {
+        self.read((sheet, row, col));
+        let id = &self.external[sheet - self.sheets.len()];
+        let cell = CellRef { row, col, row_abs: false, col_abs: false };
+        self.source.and_then(|s| s.value(id, cell)).unwrap_or(Value::Text("".to_string()))
+    }
+
+    /// The value of a workbook name; `#NAME?` for one the workbook does not have.
+    fn lookup(&mut self, name: &str) -> Value {
+        let index = match self.name_index(name) {
+            Some(index) => index,
+            None => return Value::error(ErrorKind::Name),
+        };
+        if !self.pending_names.insert(index) {
+            return Value::error_with(ErrorKind::Ref, "circular reference");
+        }
+        let programs = self.programs.clone();
+        let v = self.run(&programs[index], &mut Vec::new());
+        self.pending_names.remove(&index);
+        v
+    }
+
+    /// Runs a compiled formula. `frame` holds the values of the LET variables and LAMBDA
+    /// parameters in scope, by slot.
+    fn run(&mut self, program: &Program, frame: &mut Vec<Value>) -> Value {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut next = 0;
+        while let Some(op) = program.code.get(next) {
+            next += 1;
+            let v = match op {
+                Op::Push(v) => v.clone(),
+                Op::Load(sheet, at) => match self.target(sheet.as_deref()) {
+                    Some(sheet) => self.cell((sheet, at.row, at.col)),
+                    None => Value::error(ErrorKind::Ref),
+                },
+                Op::LoadRange(sheet, from, to) => match self.target(sheet.as_deref()) {
+                    Some(sheet) => self.range(sheet, (from.row, from.col), (to.row, to.col)),
+                    None => Value::error(ErrorKind::Ref),
+                },
+                Op::LoadSpill(sheet, at) => match self.target(sheet.as_deref()) {
+                    Some(sheet) => self.raw((sheet, at.row, at.col)).unwrap_or(Value::Text("".to_string())),
+                    None => Value::error(ErrorKind::Ref),
+                },
+                Op::Local(slot) => frame[*slot].clone(),
+                Op::Bind => {
+                    frame.extend(stack.pop());
+                    continue;
+                },
+                Op::Unbind(n) => {
+                    frame.truncate(frame.len() - n);
+                    continue;
+                },
+                Op::Name(name) => self.lookup(name),
+                Op::Call(name, n) => {
+                    let args = stack.split_off(stack.len() - n);
+                    self.call(name, args)
+                },
+                Op::Operator(op) => {
+                    let args = stack.split_off(stack.len() - 2);
+                    builtin(op, args, &self.settings)
+                },
+                Op::Apply(n) => {
+                    let args = stack.split_off(stack.len() - n);
+                    let callee = stack.pop().unwrap_or(Value::error(ErrorKind::Value));
+                    self.apply(callee, args)
+                },
+                Op::Lambda(lambda) => Value::Lambda(Arc::new(Closure { lambda: lambda.clone(), frame: frame.clone() })),
+                Op::Test(end) => match stack.pop() {
+                    Some(Value::Error(e)) => {
+                        next = *end;
+                        Value::Error(e)
+                    },
+                    cond => match cond.and_then(|cond| self.settings.coercion.boolean(&cond)) {
+                        Some(b) => Value::Bool(b),
+                        None => {
+                            next = *end;
+                            Value::error(ErrorKind::Value)
+                        },
+                    },
+                },
+                Op::JumpIfFalse(to) => {
+                    if let Some(Value::Bool(false)) = stack.pop() {
+                        next = *to;
+                    }
+                    continue;
                 },
-                Node::CellSequence(t) => { 
-                    self.resolve(&t.values);
+                Op::Jump(to) => {
+                    next = *to;
+                    continue;
                 },
-                Node::OperationDivide(t) => {
-                    self.eval_expr(&t.values); 
+                Op::Decide(decisive, end) => {
+                    let v = stack.pop().unwrap_or(Value::error(ErrorKind::Value));
+                    if let Some(e) = flatten(std::slice::from_ref(&v)).into_iter().find(|v| matches!(v, Value::Error(_))) {
+                        next = *end;
+                        e
+                    } else {
+                        match aggregate_bools(&[v], self.settings.coercion) {
+                            Some(a) if a.contains(decisive) => {
+                                next = *end;
+                                Value::Bool(*decisive)
+                            },
+                            Some(_) => continue,
+                            None => {
+                                next = *end;
+                                Value::error(ErrorKind::Value)
+                            },
+                        }
+                    }
                 },
-                Node::OperationConcat(t) => { 
-                    self.eval_expr(&t.values); 
+            };
+            stack.push(v);
+        }
+        stack.pop().unwrap_or(Value::error(ErrorKind::Value))
+    }
+
+    /// Calls a function from the registry by name.
+    fn call(&self, name: &str, args: Vec<Value>) -> Value {
+        let function = match self.functions.get(name) {
+            Some(f) => f,
+            None => return Value::error(ErrorKind::Name),
+        };
+        if !function.arity().accepts(args.len()) {
+            return Value::error_with(ErrorKind::Value, "wrong number of arguments");
+        }
+        if let Some(e) = args.iter().find(|v| matches!(v, Value::Error(_))) {
+            return e.clone();
+        }
+        function.evaluate(&args, &self.settings)
+    }
+
+    fn apply(&mut self, callee: Value, args: Vec<Value>) -> Value {
+        let closure = match callee {
+            Value::Lambda(c) => c,
+            Value::Error(e) => return Value::Error(e),
+            _ => return Value::error_with(ErrorKind::Value, "not a function"),
+        };
+        if closure.lambda.params.len() != args.len() {
+            return Value::error_with(ErrorKind::Value, "wrong number of arguments");
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+            return Value::error_with(ErrorKind::Num, "recursion too deep");
+        }
+        let mut frame = closure.frame.clone();
+        frame.extend(args);
+        self.depth += 1;
+        let v = self.run(&closure.lambda.body, &mut frame);
+        self.depth -= 1;
+        v
+    }
+
+}
+
+/// The formula cells among `cells` in dependency levels: each formula comes a level after every
+/// formula it might read. Those reading a blank cell are left out along with every formula
+/// reading them, to be evaluated in order afterwards, since it takes evaluating the formulas
+/// above and to the left to know whether an array spills there. `None` if some of the others
+/// might read each other in a cycle.
+fn levels(cells: &[Addr], reads: &HashMap<Addr, (Vec<Addr>, Vec<usize>)>) -> Option<Vec<Vec<Addr>>> {
+    let mut readers: HashMap<Addr, Vec<Addr>> = HashMap::new();
+    for at in cells {
+        for target in reads.get(at).into_iter().flat_map(|(targets, _)| targets) {
+            readers.entry(*target).or_default().push(*at);
+        }
+    }
+    let mut later: HashSet<Addr> = cells.iter().copied().filter(|at| reads.get(at).is_some_and(|(_, blank)| !blank.is_empty())).collect();
+    let mut queue: Vec<Addr> = later.iter().copied().collect();
+    while let Some(at) = queue.pop() {
+        for reader in readers.get(&at).into_iter().flatten() {
+            if later.insert(*reader) {
+                queue.push(*reader);
+            }
+        }
+    }
+    let mut waiting: HashMap<Addr, usize> = cells.iter().filter(|at| !later.contains(at))
+        .filter_map(|at| reads.get(at).map(|(targets, _)| (*at, targets.len()))).collect();
+    let total = waiting.len();
+    let mut level: Vec<Addr> = waiting.iter().filter(|(_, n)| **n == 0).map(|(at, _)| *at).collect();
+    let mut levels: Vec<Vec<Addr>> = Vec::new();
+    let mut done = 0;
+    while !level.is_empty() {
+        level.sort();
+        done += level.len();
+        let mut next: Vec<Addr> = Vec::new();
+        for at in &level {
+            for reader in readers.get(at).into_iter().flatten() {
+                if let Some(n) = waiting.get_mut(reader) {
+                    *n -= 1;
+                    if *n == 0 {
+                        next.push(*reader);
+                    }
+                }
+            }
+        }
+        levels.push(level);
+        level = next;
+    }
+    (done == total).then_some(levels)
+}
+
+/// Parses the target of a defined name, e.g. `sheet-1!B2` or `sheet-2!A1:A50`.
+fn reference(text: &str) -> Option<Node> {
+    let (sheet, cells) = match text.rsplit_once('!') {
+        Some((sheet, cells)) => (Some(Token::new(TokenKind::Identifier, sheet.to_string())), cells),
+        None => (None, text),
+    };
+    let (from, to) = match cells.split_once(':') {
+        Some((from, to)) => (from, Some(to)),
+        None => (cells, None),
+    };
+    let reference = CellRef::parse(from)?;
+    match to {
+        Some(to) => Some(Node::Range(RangeNode { from: reference, to: CellRef::parse(to)?, sheet })),
+        None => {
+            let token = Token::new(TokenKind::Notation, from.to_string());
+            Some(Notation(NotationNode { token, reference, sheet }))
+        },
+    }
+}
+
+/// Rewrites a formula written in cell `from` so it can be placed in cell `to`,
+/// shifting relative references the way copy-paste and fill-down do, e.g.
+/// `translate("=A1*$B$1", "C1", "C2")` gives `=A2*$B$1`. Returns `None` when
+/// a cell is invalid, the formula does not lex or parse, or a reference would
+/// move off the sheet.
+pub fn translate(formula: &str, from: &str, to: &str) -> Option<String> {
+    let from = CellRef::parse(from)?;
+    let to = CellRef::parse(to)?;
+    let rows = to.row as isize - from.row as isize;
+    let cols = to.col as isize - from.col as isize;
+    let mut tokens = Operation::new(formula.to_string()).lex_exact()?;
+    Parser::new(tokens.clone()).parse_formula()?;
+    for t in tokens.iter_mut().filter(|t| t.kind == TokenKind::Notation) {
+        t.literal = CellRef::parse(&t.literal)?.offset(rows, cols)?.to_string();
+    }
+    Some(render(&tokens))
+}
+
+/// Writes lexed formula tokens back out as text, without the whitespace between them.
+fn render(tokens: &[Token]) -> String {
+    let mut res = String::new();
+    for (i, t) in tokens.iter().enumerate() {
+        match t.kind {
+            TokenKind::Identifier if tokens.get(i + 1).is_some_and(|n| n.kind == TokenKind::Bang) => {
+                res.push_str(&format!("'{}'", t.literal));
+            },
+            TokenKind::Assign => res.push('='),
+            TokenKind::Op_Paren_o => res.push('('),
+            TokenKind::Op_Paren_c => res.push(')'),
+            TokenKind::Comma => res.push(','),
+            TokenKind::Punc => res.push(':'),
+            TokenKind::Hash => res.push('#'),
+            TokenKind::Bang => res.push('!'),
+            _ => res.push_str(&t.literal),
+        }
+    }
+    res
+}
+
+/// Evaluates one formula on its own, e.g. `evaluate_formula("=SUM(A1, 2)", &cells)`,
+/// with `cells` supplying every cell it references. Input without a leading `=` is a literal.
+pub fn evaluate_formula(formula: &str, cells: &dyn CellSource) -> Value {
+    evaluate_formula_with(formula, cells, &Registry::new(), Settings::default())
+}
+
+/// Like `evaluate_formula`, but the formula may also call the functions registered in
+/// `functions` and evaluates under `settings`.
+pub fn evaluate_formula_with(formula: &str, cells: &dyn CellSource, functions: &Registry, settings: Settings) -> Value {
+    let Some((_, mut node)) = parse_input(formula) else {
+        return Value::error_with(ErrorKind::Value, "formula does not parse");
+    };
+    check(&mut node, functions, settings.coercion, &[], &mut Vec::new());
+    Interpreter::new(&[], &[], functions, settings).with_source(cells).evaluate(&node)
+}
+
+/// A problem found by `Node::validate`, e.g. `sheet-1!B2: wrong number of arguments to NOT: expected 1, got 2`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The cell (`sheet-1!B2`) or workbook name the formula belongs to.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Checks every call to a registered function inside `node`, replacing a call that
+/// can never succeed with an `Invalid` node and recording why in `problems`.
+/// Calls of LET variables, LAMBDA parameters and workbook names are left alone.
+fn check(node: &mut Node, functions: &Registry, coercion: Coercion, bound: &[String], problems: &mut Vec<String>) {
+    let invalid = match node {
+        Node::Call(t) => {
+            for arg in t.args.iter_mut() {
+                check(arg, functions, coercion, bound, problems);
+            }
+            let name = &t.name.literal;
+            let shadowed = bound.iter().any(|b| b.eq_ignore_ascii_case(name));
+            match functions.get(name) {
+                Some(function) if !shadowed => signature(function, &t.args, coercion).map(|message| InvalidNode { message, span: t.span.clone() }),
+                _ => None,
+            }
+        },
+        Node::Apply(t) => {
+            check(&mut t.callee, functions, coercion, bound, problems);
+            for arg in t.values.iter_mut() {
+                check(arg, functions, coercion, bound, problems);
+            }
+            None
+        },
+        Node::Binary(t) => {
+            check(&mut t.lhs, functions, coercion, bound, problems);
+            check(&mut t.rhs, functions, coercion, bound, problems);
+            None
+        },
+        Node::OperationLet(t) => {
+            let mut inner = bound.to_vec();
+            for (name, value) in t.names.iter().zip(t.values.iter_mut()) {
+                check(value, functions, coercion, &inner, problems);
+                inner.push(name.literal.clone());
+            }
+            check(&mut t.body, functions, coercion, &inner, problems);
+            None
+        },
+        Node::OperationLambda(t) => {
+            let mut inner = bound.to_vec();
+            inner.extend(t.params.iter().map(|p| p.literal.clone()));
+            if let Some(body) = Arc::get_mut(&mut t.body) {
+                check(body, functions, coercion, &inner, problems);
+            }
+            None
+        },
+        _ => None,
+    };
+    if let Some(invalid) = invalid {
+        problems.push(invalid.message.clone());
+        *node = Node::Invalid(invalid);
+    }
+}
+
+/// Why `args` can never be passed to `function`, judging by their count and by
+/// literals that `coercion` cannot turn into the parameter's kind.
+fn signature(function: &dyn Function, args: &[Node], coercion: Coercion) -> Option<String> {
+    let name = function.name().to_ascii_uppercase();
+    let arity = function.arity();
+    if !arity.accepts(args.len()) {
+        return Some(format!("wrong number of arguments to {}: expected {}, got {}", name, arity, args.len()));
+    }
+    let params = function.params();
+    for (i, arg) in args.iter().enumerate() {
+        let expected = params.get(i.min(params.len().saturating_sub(1))).copied().unwrap_or(Kind::Any);
+        let actual = match arg {
+            Node::IntLit(_) | Node::FloatLit(_) => Kind::Number,
+            Node::BoolLit(_) => Kind::Bool,
+            Node::StringLit(_) => Kind::Text,
+            _ => continue,
+        };
+        let value = literal(arg);
+        let converts = match expected {
+            Kind::Any => true,
+            Kind::Number => coercion.number(&value).is_some(),
+            Kind::Bool => coercion.boolean(&value).is_some(),
+            Kind::Text => coercion.text(&value).is_some(),
+        };
+        if !converts {
+            return Some(format!("argument {} of {} must be {}, got {}", i + 1, name, expected, actual));
+        }
+    }
+    None
+}
+
+/// The value of a literal node; anything else is `#VALUE!`.
+fn literal(node: &Node) -> Value {
+    match node {
+        Node::IntLit(t) if t.token.literal.parse::<i64>().is_ok() => Value::Int(t.token.literal.parse().unwrap()),
+        Node::IntLit(t) | Node::FloatLit(t) => match t.token.literal.parse::<f64>() {
+            Ok(n) => Value::Number(n),
+            Err(_) => Value::error(ErrorKind::Value),
+        },
+        Node::BoolLit(t) => Value::Bool(t.token.literal.eq_ignore_ascii_case("true")),
+        Node::StringLit(t) => {
+            let s = &t.token.literal;
+            if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+                Value::Text(s[1..s.len() - 1].to_string())
+            } else {
+                Value::Text(s.to_string())
+            }
+        },
+        _ => Value::error(ErrorKind::Value),
+    }
+}
+
+fn builtin(name: &str, args: Vec<Value>, settings: &Settings) -> Value {
+    if let Some(e) = args.iter().find(|v| matches!(v, Value::Error(_))) {
+        return e.clone();
+    }
+    let mismatch = Value::error(ErrorKind::Value);
+    let coercion = settings.coercion;
+    let numeric = settings.numeric;
+    let name = name.to_ascii_uppercase();
+    let elementwise = ["+", "-", "*", "/", "DIVIDE", "EQ", "NE", "GT", "LT", "GTE", "LTE", "NOT"];
+    if elementwise.contains(&name.as_str()) && args.iter().any(|v| matches!(v, Value::Array(_))) {
+        return lift(&name, &args, settings);
+    }
+    // Errors inside arrays propagate too, except through functions that only reshape their input.
+    let reshaping = ["FILTER", "SORT", "UNIQUE", "TRANSPOSE"];
+    if !reshaping.contains(&name.as_str()) {
+        if let Some(e) = flatten(&args).into_iter().find(|v| matches!(v, Value::Error(_))) {
+            return e;
+        }
+    }
+    match name.as_str() {
+        "SUM" | "+" => {
+            if let Some(n) = ints(&args).and_then(|a| a.iter().try_fold(0i64, |acc, x| acc.checked_add(*x))) {
+                return Value::Int(n);
+            }
+            match aggregate_numbers(&args, coercion) {
+                Some(a) => Value::Number(numeric.sum(&a)),
+                None => mismatch,
+            }
+        },
+        "-" => {
+            if let Some([a, b]) = ints(&args).as_deref() {
+                if let Some(n) = a.checked_sub(*b) {
+                    return Value::Int(n);
+                }
+            }
+            match numbers(&args, coercion) {
+                Some(a) if a.len() == 2 => Value::Number(numeric.difference(a[0], a[1])),
+                _ => mismatch,
+            }
+        },
+        "MULTIPLY" | "*" => {
+            if let Some(n) = ints(&args).and_then(|a| a.iter().try_fold(1i64, |acc, x| acc.checked_mul(*x))) {
+                return Value::Int(n);
+            }
+            match aggregate_numbers(&args, coercion) {
+                Some(a) => Value::Number(numeric.product(&a)),
+                None => mismatch,
+            }
+        },
+        "DIVIDE" | "/" => {
+            if let Some([a, b]) = ints(&args).as_deref() {
+                if *b != 0 && a.checked_rem(*b) == Some(0) {
+                    return Value::Int(a / b);
+                }
+            }
+            match numbers(&args, coercion) {
+                Some(a) if a.len() == 2 && a[1] == 0.0 => Value::error(ErrorKind::Div0),
+                Some(a) if a.len() == 2 => Value::Number(numeric.quotient(a[0], a[1])),
+                _ => mismatch,
+            }
+        },
+        "EQ" | "NE" | "GT" | "LT" | "GTE" | "LTE" => {
+            match args.as_slice() {
+                [a, b] if !matches!(a, Value::Lambda(_)) && !matches!(b, Value::Lambda(_)) => {
+                    let o = compare(a, b, numeric);
+                    Value::Bool(match name.as_str() {
+                        "EQ" => o == Ordering::Equal,
+                        "NE" => o != Ordering::Equal,
+                        "GT" => o == Ordering::Greater,
+                        "LT" => o == Ordering::Less,
+                        "GTE" => o != Ordering::Less,
+                        _ => o != Ordering::Greater,
+                    })
                 },
-                Node::OperationIf(t) => {
-                    self.eval_expr(&t.values);  
+                _ => mismatch,
+            }
+        },
+        "NOT" => {
+            match bools(&args, coercion) {
+                Some(a) if a.len() == 1 => Value::Bool(!a[0]),
+                _ => mismatch,
+            }
+        },
+        "AND" => {
+            match aggregate_bools(&args, coercion) {
+                Some(a) => Value::Bool(a.iter().all(|b| *b)),
+                None => mismatch,
+            }
+        },
+        "OR" => {
+            match aggregate_bools(&args, coercion) {
+                Some(a) => Value::Bool(a.iter().any(|b| *b)),
+                None => mismatch,
+            }
+        },
+        "IF" => {
+            match args.as_slice() {
+                [c, a, b] => match coercion.boolean(c) {
+                    Some(c) => if c { a.clone() } else { b.clone() },
+                    None => mismatch,
                 },
-                Node::OperationOr(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "CONCAT" => {
+            let mut concat_res = "".to_string();
+            for x in &flatten(&args) {
+                match coercion.text(x) {
+                    Some(t) => concat_res.push_str(&t),
+                    None => return mismatch,
+                }
+            }
+            Value::Text(concat_res)
+        },
+        "PMT" => {
+            match numbers(&args, coercion) {
+                Some(a) if a.len() >= 3 && a.len() <= 5 => {
+                    let fv = a.get(3).copied().unwrap_or(0.0);
+                    let when = timing(a.get(4));
+                    finite(pmt(a[0], a[1], a[2], fv, when))
                 },
-                Node::OperationNot(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "PV" => {
+            match numbers(&args, coercion) {
+                Some(a) if a.len() >= 3 && a.len() <= 5 => {
+                    let fv = a.get(3).copied().unwrap_or(0.0);
+                    let when = timing(a.get(4));
+                    finite(pv(a[0], a[1], a[2], fv, when))
                 },
-                Node::OperationAnd(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "FV" => {
+            match numbers(&args, coercion) {
+                Some(a) if a.len() >= 3 && a.len() <= 5 => {
+                    let pv = a.get(3).copied().unwrap_or(0.0);
+                    let when = timing(a.get(4));
+                    finite(fv(a[0], a[1], a[2], pv, when))
                 },
-                Node::OperationMultiply(t) => {
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "NPV" => {
+            match aggregate_numbers(&args, coercion) {
+                Some(a) if a.len() >= 2 => finite(npv(a[0], &a[1..])),
+                _ => mismatch,
+            }
+        },
+        "IRR" => {
+            let guess = args.get(1).map_or(Some(0.1), |g| coercion.number(g));
+            let values = args.first().and_then(|v| aggregate_numbers(std::slice::from_ref(v), coercion));
+            match (values, guess) {
+                (Some(a), Some(guess)) if args.len() <= 2 => {
+                    match irr(&a, guess) {
+                        Some(r) => Value::Number(r),
+                        None => Value::error_with(ErrorKind::Num, "did not converge"),
+                    }
                 },
-                Node::OperationGt(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "RATE" => {
+            match numbers(&args, coercion) {
+                Some(a) if a.len() >= 3 && a.len() <= 6 => {
+                    let fv = a.get(3).copied().unwrap_or(0.0);
+                    let when = timing(a.get(4));
+                    let guess = a.get(5).copied().unwrap_or(0.1);
+                    match rate(a[0], a[1], a[2], fv, when, guess) {
+                        Some(r) => Value::Number(r),
+                        None => Value::error_with(ErrorKind::Num, "did not converge"),
+                    }
                 },
-                Node::OperationEq(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "NPER" => {
+            match numbers(&args, coercion) {
+                Some(a) if a.len() >= 3 && a.len() <= 5 => {
+                    let fv = a.get(3).copied().unwrap_or(0.0);
+                    let when = timing(a.get(4));
+                    finite(nper(a[0], a[1], a[2], fv, when))
                 },
-                Node::OperationSum(t) => { 
-                    self.eval_expr(&t.values); 
+                _ => mismatch,
+            }
+        },
+        "SEQUENCE" => {
+            match numbers(&args, coercion) {
+                Some(a) if !a.is_empty() && a.len() <= 4 => {
+                    let rows = a[0].trunc();
+                    let cols = a.get(1).copied().unwrap_or(1.0).trunc();
+                    let start = a.get(2).copied().unwrap_or(1.0);
+                    let step = a.get(3).copied().unwrap_or(1.0);
+                    if rows < 1.0 || cols < 1.0 {
+                        return Value::error(ErrorKind::Calc);
+                    }
+                    let too_large = Value::error_with(ErrorKind::Num, "array too large");
+                    if rows > MAX_ROWS as f64 || cols > MAX_COLUMNS as f64 {
+                        return too_large;
+                    }
+                    let (rows, cols) = (rows as usize, cols as usize);
+                    if rows.checked_mul(cols).is_none_or(|n| n > MAX_ARRAY_CELLS) {
+                        return too_large;
+                    }
+                    let whole = args.iter().skip(2).all(|v| matches!(v, Value::Int(_)));
+                    let item = |k: usize| {
+                        let x = start + step * k as f64;
+                        if whole && x.abs() < 9007199254740992.0 { Value::Int(x as i64) } else { Value::Number(x) }
+                    };
+                    let grid = (0..rows).map(|i| (0..cols).map(|j| item(i * cols + j)).collect()).collect();
+                    Value::Array(Arc::new(grid))
                 },
-                Node::Notation(t) => { 
+                _ => mismatch,
+            }
+        },
+        "FILTER" => {
+            if args.len() < 2 || args.len() > 3 {
+                return mismatch;
+            }
+            let array = grid(&args[0]);
+            let include = grid(&args[1]);
+            let by_rows = include.len() == array.len() && include.iter().all(|r| r.len() == 1);
+            let keep: Option<Vec<bool>> = if by_rows {
+                bools(&flatten(&args[1..2]), coercion)
+            } else if include.len() == 1 && include[0].len() == array[0].len() {
+                bools(&include[0], coercion)
+            } else {
+                None
+            };
+            let keep = match keep {
+                Some(k) => k,
+                None => return mismatch,
+            };
+            let filtered: Vec<Vec<Value>> = if by_rows {
+                array.iter().zip(&keep).filter(|(_, k)| **k).map(|(r, _)| r.clone()).collect()
+            } else {
+                array.iter().map(|r| r.iter().zip(&keep).filter(|(_, k)| **k).map(|(v, _)| v.clone()).collect()).collect()
+            };
+            if filtered.is_empty() || filtered[0].is_empty() {
+                return match args.get(2) {
+                    Some(v) => v.clone(),
+                    None => Value::error(ErrorKind::Calc),
+                };
+            }
+            Value::Array(Arc::new(filtered))
+        },
+        "SORT" => {
+            if args.is_empty() || args.len() > 4 {
+                return mismatch;
+            }
+            let by_col = match args.get(3).map(|v| coercion.boolean(v)) {
+                Some(Some(b)) => b,
+                None => false,
+                Some(None) => return mismatch,
+            };
+            let options = match numbers(&args[1..args.len().min(3)], coercion) {
+                Some(o) => o,
+                None => return mismatch,
+            };
+            let index = options.first().copied().unwrap_or(1.0).trunc();
+            let order = options.get(1).copied().unwrap_or(1.0);
+            let mut array = grid(&args[0]);
+            if by_col {
+                array = transpose(&array);
+            }
+            if index < 1.0 || index as usize > array[0].len() || (order != 1.0 && order != -1.0) {
+                return mismatch;
+            }
+            let index = index as usize - 1;
+            array.sort_by(|a, b| {
+                let o = compare(&a[index], &b[index], numeric);
+                if order < 0.0 { o.reverse() } else { o }
+            });
+            if by_col {
+                array = transpose(&array);
+            }
+            Value::Array(Arc::new(array))
+        },
+        "UNIQUE" => {
+            if args.is_empty() || args.len() > 3 {
+                return mismatch;
+            }
+            let flags = match bools(&args[1..], coercion) {
+                Some(f) => f,
+                None => return mismatch,
+            };
+            let by_col = flags.first().copied().unwrap_or(false);
+            let exactly_once = flags.get(1).copied().unwrap_or(false);
+            let mut array = grid(&args[0]);
+            if by_col {
+                array = transpose(&array);
+            }
+            let same = |a: &Vec<Value>, b: &Vec<Value>| a.len() == b.len() && a.iter().zip(b).all(|(x, y)| compare(x, y, numeric) == Ordering::Equal);
+            let mut unique: Vec<Vec<Value>> = Vec::new();
+            for r in &array {
+                let count = array.iter().filter(|o| same(o, r)).count();
+                if !unique.iter().any(|u| same(u, r)) && (!exactly_once || count == 1) {
+                    unique.push(r.clone());
+                }
+            }
+            if unique.is_empty() {
+                return Value::error(ErrorKind::Calc);
+            }
+            if by_col {
+                unique = transpose(&unique);
+            }
+            Value::Array(Arc::new(unique))
+        },
+        "TRANSPOSE" => {
+            match args.as_slice() {
+                [a] => Value::Array(Arc::new(transpose(&grid(a)))),
+                _ => mismatch,
+            }
+        },
+        _ => Value::error_with(ErrorKind::Name, &format!("unknown function {}", name)),
+    }
+}
 
-                },
-                Node::FloatLit(t) => { 
+/// Applies a scalar function element by element, e.g. `GT(A1:A3, 1)` gives three booleans.
+fn lift(name: &str, args: &[Value], settings: &Settings) -> Value {
+    let grids: Vec<Vec<Vec<Value>>> = args.iter().map(grid).collect();
+    let rows = grids.iter().map(|g| g.len()).max().unwrap_or(0);
+    let cols = grids.iter().map(|g| g.iter().map(|r| r.len()).max().unwrap_or(0)).max().unwrap_or(0);
+    let mut res:Vec<Vec<Value>> = Vec::new();
+    for i in 0..rows {
+        let mut r:Vec<Value> = Vec::new();
+        for j in 0..cols {
+            let item = grids.iter().map(|g| {
+                let row = g.get(if g.len() == 1 { 0 } else { i });
+                let v = row.and_then(|row| row.get(if row.len() == 1 { 0 } else { j }));
+                v.cloned().unwrap_or(Value::error(ErrorKind::NA))
+            }).collect();
+            r.push(builtin(name, item, settings));
+        }
+        res.push(r);
+    }
+    Value::Array(Arc::new(res))
+}
+
+/// Rows of an array argument; a scalar is treated as a 1x1 array.
+fn grid(v: &Value) -> Vec<Vec<Value>> {
+    match v {
+        Value::Array(a) => a.as_ref().clone(),
+        _ => vec![vec![v.clone()]],
+    }
+}
+
+fn flatten(stack: &[Value]) -> Vec<Value> {
+    let mut res:Vec<Value> = Vec::new();
+    for x in stack {
+        match x {
+            Value::Array(a) => a.iter().for_each(|r| res.extend(r.iter().cloned())),
+            _ => res.push(x.clone()),
+        }
+    }
+    res
+}
+
+fn transpose(array: &[Vec<Value>]) -> Vec<Vec<Value>> {
+    let cols = array.iter().map(|r| r.len()).max().unwrap_or(0);
+    (0..cols).map(|j| array.iter().map(|r| r.get(j).cloned().unwrap_or(Value::Text("".to_string()))).collect()).collect()
+}
+
+/// Sort order used by SORT, UNIQUE and the comparison functions: numbers before
+/// text before booleans, text compared without regard to case. Values of different
+/// types are never equal, so `EQ("3", 3)` is false in every coercion mode.
+fn compare(a: &Value, b: &Value, numeric: Numeric) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::Int(_) | Value::Number(_) => 0,
+            Value::Text(_) => 1,
+            Value::Bool(_) => 2,
+            _ => 3,
+        }
+    }
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => x.cmp(y),
+        (Value::Int(_) | Value::Number(_), Value::Int(_) | Value::Number(_)) => {
+            numeric.compare(Coercion::Strict.number(a).unwrap_or(0.0), Coercion::Strict.number(b).unwrap_or(0.0))
+        },
+        (Value::Text(x), Value::Text(y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Newton iterations allowed for IRR and RATE before giving up.
+const MAX_ITERATIONS: usize = 100;
+const TOLERANCE: f64 = 1e-10;
+
+/// The arguments as exact integers, with arrays flattened and the blanks in them skipped;
+/// `None` unless every other item is an `Int` or a blank, which counts as 0.
+fn ints(args: &[Value]) -> Option<Vec<i64>> {
+    let int = |x: &Value| match x {
+        Value::Int(n) => Some(*n),
+        _ if blank(x) => Some(0),
+        _ => None,
+    };
+    let mut res: Vec<i64> = Vec::new();
+    for x in args {
+        match x {
+            Value::Array(a) => {
+                for v in a.iter().flatten().filter(|v| !blank(v)) {
+                    res.push(int(v)?);
+                }
+            },
+            _ => res.push(int(x)?),
+        }
+    }
+    Some(res)
+}
+
+/// Whether `v` is what a blank cell holds.
+fn blank(v: &Value) -> bool {
+    matches!(v, Value::Text(s) if s.is_empty())
+}
+
+/// The items of an array argument, without its blanks.
+fn filled(x: &Value) -> Vec<Value> {
+    flatten(std::slice::from_ref(x)).into_iter().filter(|v| !blank(v)).collect()
+}
+
+fn numbers(stack: &[Value], coercion: Coercion) -> Option<Vec<f64>> {
+    stack.iter().map(|x| coercion.number(x)).collect()
+}
+
+fn bools(stack: &[Value], coercion: Coercion) -> Option<Vec<bool>> {
+    stack.iter().map(|x| coercion.boolean(x)).collect()
+}
+
+/// Numbers in the arguments of an aggregate such as SUM, with arrays flattened.
+/// Lenient mode skips text, booleans and blanks inside arrays, as Excel does for ranges.
+fn aggregate_numbers(args: &[Value], coercion: Coercion) -> Option<Vec<f64>> {
+    let mut res:Vec<f64> = Vec::new();
+    for x in args {
+        match x {
+            Value::Array(_) if coercion == Coercion::Lenient => {
+                res.extend(flatten(std::slice::from_ref(x)).iter().filter_map(|v| match v { Value::Int(_) | Value::Number(_) => coercion.number(v), _ => None }));
+            },
+            Value::Array(_) => res.extend(numbers(&filled(x), coercion)?),
+            _ => res.push(coercion.number(x)?),
+        }
+    }
+    Some(res)
+}
+
+/// Booleans in the arguments of AND or OR, with arrays flattened.
+/// Lenient mode skips text and blanks inside arrays.
+fn aggregate_bools(args: &[Value], coercion: Coercion) -> Option<Vec<bool>> {
+    let mut res:Vec<bool> = Vec::new();
+    for x in args {
+        match x {
+            Value::Array(_) if coercion == Coercion::Lenient => {
+                res.extend(flatten(std::slice::from_ref(x)).iter().filter(|v| !matches!(v, Value::Text(_))).filter_map(|v| coercion.boolean(v)));
+            },
+            Value::Array(_) => res.extend(bools(&filled(x), coercion)?),
+            _ => res.push(coercion.boolean(x)?),
+        }
+    }
+    Some(res)
+}
+
+/// The `type` argument of the loan functions: 1 when payments fall at the start of each
+/// period, for any non-zero value as in Excel, else 0 for the end.
+fn timing(when: Option<&f64>) -> f64 {
+    if when.is_some_and(|w| *w != 0.0) { 1.0 } else { 0.0 }
+}
+
+fn finite(x: f64) -> Value {
+    if x.is_finite() {
+        Value::Number(x)
+    } else {
+        Value::error_with(ErrorKind::Num, "invalid number")
+    }
+}
+
+/// Periodic payment of a loan (`when` is 0 for end-of-period payments, 1 for beginning).
+pub fn pmt(rate: f64, nper: f64, pv: f64, fv: f64, when: f64) -> f64 {
+    if rate == 0.0 {
+        return -(pv + fv) / nper;
+    }
+    let growth = (1.0 + rate).powf(nper);
+    -(rate * (fv + pv * growth)) / ((1.0 + rate * when) * (growth - 1.0))
+}
+
+pub fn pv(rate: f64, nper: f64, pmt: f64, fv: f64, when: f64) -> f64 {
+    if rate == 0.0 {
+        return -(fv + pmt * nper);
+    }
+    let growth = (1.0 + rate).powf(nper);
+    -(fv + pmt * (1.0 + rate * when) * (growth - 1.0) / rate) / growth
+}
+
+pub fn fv(rate: f64, nper: f64, pmt: f64, pv: f64, when: f64) -> f64 {
+    if rate == 0.0 {
+        return -(pv + pmt * nper);
+    }
+    let growth = (1.0 + rate).powf(nper);
+    -(pv * growth + pmt * (1.0 + rate * when) * (growth - 1.0) / rate)
+}
+
+/// Net present value of cash flows received at the end of periods 1, 2, ...
+pub fn npv(rate: f64, values: &[f64]) -> f64 {
+    let mut result = 0.0;
+    let mut discount = 1.0;
+    for v in values {
+        discount *= 1.0 + rate;
+        result += v / discount;
+    }
+    result
+}
+
+pub fn nper(rate: f64, pmt: f64, pv: f64, fv: f64, when: f64) -> f64 {
+    if rate == 0.0 {
+        return -(pv + fv) / pmt;
+    }
+    let a = pmt * (1.0 + rate * when);
+    ((a - fv * rate) / (a + pv * rate)).ln() / (1.0 + rate).ln()
+}
+
+/// Internal rate of return of cash flows starting at period 0. Returns `None`
+/// when Newton's method does not converge within `MAX_ITERATIONS`.
+pub fn irr(values: &[f64], guess: f64) -> Option<f64> {
+    if !values.iter().any(|v| *v > 0.0) || !values.iter().any(|v| *v < 0.0) {
+        return None;
+    }
+    let mut r = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let mut f = 0.0;
+        let mut df = 0.0;
+        for (t, v) in values.iter().enumerate() {
+            let t = t as f64;
+            f += v / (1.0 + r).powf(t);
+            df -= t * v / (1.0 + r).powf(t + 1.0);
+        }
+        if df == 0.0 || !df.is_finite() {
+            return None;
+        }
+        let next = r - f / df;
+        if !next.is_finite() || next <= -1.0 {
+            return None;
+        }
+        if (next - r).abs() < TOLERANCE {
+            return Some(next);
+        }
+        r = next;
+    }
+    None
+}
+
+/// Interest rate per period of an annuity, solved with Newton's method.
+pub fn rate(nper: f64, pmt: f64, pv: f64, fv: f64, when: f64, guess: f64) -> Option<f64> {
+    let mut r = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let (f, df) = if r.abs() < TOLERANCE {
+            (pv + pmt * nper + fv, pv * nper + pmt * (when * nper + nper * (nper - 1.0) / 2.0))
+        } else {
+            let growth = (1.0 + r).powf(nper);
+            let dgrowth = nper * (1.0 + r).powf(nper - 1.0);
+            let f = pv * growth + pmt * (1.0 + r * when) * (growth - 1.0) / r + fv;
+            let df = pv * dgrowth
+                + pmt * (when * (growth - 1.0) / r + (1.0 + r * when) * (dgrowth * r - (growth - 1.0)) / (r * r));
+            (f, df)
+        };
+        if df == 0.0 || !df.is_finite() {
+            return None;
+        }
+        let next = r - f / df;
+        if !next.is_finite() || next <= -1.0 {
+            return None;
+        }
+        if (next - r).abs() < TOLERANCE {
+            return Some(next);
+        }
+        r = next;
+    }
+    None
+}
+
+impl Node {
+
+    /// Checks the arity and literal argument types of every function call in the payload.
+    /// Calls that can never succeed are replaced so their cell evaluates to the problem as an error.
+    pub fn validate(&mut self, functions: &Registry, settings: &Settings) -> Vec<Diagnostic> {
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        let t = match self {
+            Node::Evaluator(t) => t,
+            _ => return diagnostics,
+        };
+        let bound: Vec<String> = t.names.iter().map(|n| n.token.literal.clone()).collect();
+        for name in t.names.iter_mut() {
+            let mut problems: Vec<String> = Vec::new();
+            check(&mut name.value, functions, settings.coercion, &bound, &mut problems);
+            diagnostics.extend(problems.into_iter().map(|message| Diagnostic { location: name.token.literal.clone(), message }));
+        }
+        for spreadsheet in t.values.iter_mut() {
+            let Node::Spreadsheet(spreadsheet) = spreadsheet else { continue };
+            for sheet in spreadsheet.values.iter_mut() {
+                let Node::Sheet(sheet) = sheet else { continue };
+                let mut row = 0;
+                for data in sheet.values.iter_mut() {
+                    let Node::DataCells(data) = data else { continue };
+                    for cells in data.values.iter_mut() {
+                        let Node::CellSequence(cells) = cells else { continue };
+                        for (col, cell) in cells.values.iter_mut().enumerate() {
+                            let mut problems: Vec<String> = Vec::new();
+                            if let Node::Invalid(t) = cell {
+                                problems.push(t.message.clone());
+                            }
+                            check(cell, functions, settings.coercion, &bound, &mut problems);
+                            let at = CellRef { row, col, row_abs: false, col_abs: false };
+                            let location = format!("{}!{}", sheet.id.literal, at);
+                            diagnostics.extend(problems.into_iter().map(|message| Diagnostic { location: location.clone(), message }));
+                        }
+                        row += 1;
+                    }
+                }
+            }
+        }
+        diagnostics
+    }
+}
+
+/// One cell of a `Workbook`: what was written in it, its parsed form and its computed value.
+/// Cells are read-only; `Workbook::set_cell` changes them and what depends on them.
+#[derive(Debug)]
+pub struct Cell {
+    input: String,
+    node: Node,
+    program: Program,
+    value: Value,
+}
+
+impl Cell {
+    /// The literal or `=formula` as written, formulas without whitespace.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// `node` compiled, which is what evaluating the cell runs.
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    fn blank() -> Self {
+        let token = Token::new(TokenKind::String, "".to_string());
+        let node = StringLit(LitNode { token });
+        Cell { input: "".to_string(), program: Compiler::default().compile(&node), node, value: Value::Text("".to_string()) }
+    }
+}
+
+/// Parses what is typed into a cell: a `=formula`, or else a number, boolean or text literal.
+fn parse_input(input: &str) -> Option<(String, Node)> {
+    if input.starts_with('=') {
+        let tokens = Operation::new(input.to_string()).lex_exact()?;
+        let text = render(&tokens);
+        return Some((text, Parser::new(tokens).parse_formula()?));
+    }
+    let numeric = input.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    let token = Token::new(TokenKind::String, input.to_string());
+    let node = if input.parse::<i64>().is_ok() {
+        IntLit(LitNode { token: Token { kind: TokenKind::Int, ..token } })
+    } else if numeric && input.parse::<f64>().is_ok() {
+        FloatLit(LitNode { token: Token { kind: TokenKind::Double, ..token } })
+    } else if input.eq_ignore_ascii_case("true") || input.eq_ignore_ascii_case("false") {
+        BoolLit(LitNode { token: Token { kind: TokenKind::Bool, ..token } })
+    } else {
+        StringLit(LitNode { token })
+    };
+    Some((input.to_string(), node))
+}
+
+/// A cell whose value changed after `Workbook::set_cell` or `Workbook::refresh`, with its new value.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub sheet: String,
+    pub cell: CellRef,
+    pub value: Value,
+}
+
+/// One sheet of a `Workbook`, its cells in the shape of the input `data` matrix.
+#[derive(Debug)]
+pub struct Sheet {
+    id: String,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Sheet {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn rows(&self) -> &[Vec<Cell>] {
+        &self.rows
+    }
+
+    /// The cell at an A1-style address such as `B3`; `$` markers are ignored.
+    pub fn cell(&self, address: &str) -> Option<&Cell> {
+        let at = CellRef::parse(address)?;
+        self.rows.get(at.row).and_then(|r| r.get(at.col))
+    }
+}
+
+/// A parsed and evaluated payload: sheets by id, workbook names and the problems validation found.
+#[derive(Debug)]
+pub struct Workbook {
+    pub url: String,
+    sheets: Vec<Sheet>,
+    names: Vec<NameNode>,
+    pub diagnostics: Vec<Diagnostic>,
+    functions: Registry,
+    settings: Settings,
+    source: Option<Box<dyn CellSource>>,
+    calculation: Calculation,
+}
+
+impl Workbook {
+    /// Lexes, parses, validates and evaluates a JSON payload.
+    pub fn from_json(json: &str, functions: Registry, settings: Settings) -> Option<Self> {
+        let tokens = Lexer::new(json.to_string()).lex();
+        Self::new(Parser::new(tokens).parse_all()?, functions, settings)
+    }
+
+    /// Builds a workbook from a parsed payload, validating and evaluating every cell.
+    pub fn new(mut payload: Node, functions: Registry, settings: Settings) -> Option<Self> {
+        let diagnostics = payload.validate(&functions, &settings);
+        let Node::Evaluator(t) = payload else { return None };
+        let compiler = Compiler::new(&t.names, &functions);
+        let mut sheets: Vec<Sheet> = Vec::new();
+        for spreadsheet in t.values.into_vec() {
+            let Node::Spreadsheet(spreadsheet) = spreadsheet else { continue };
+            for sheet in spreadsheet.values.into_vec() {
+                let Node::Sheet(sheet) = sheet else { continue };
+                let mut rows: Vec<Vec<Cell>> = Vec::new();
+                for data in sheet.values.into_vec() {
+                    let Node::DataCells(data) = data else { continue };
+                    for row in data.values.into_vec() {
+                        let Node::CellSequence(row) = row else { continue };
+                        rows.push(row.values.into_vec().into_iter().zip(row.sources)
+                            .map(|(node, input)| Cell { input, program: compiler.compile(&node), node, value: Value::Text("".to_string()) }).collect());
+                    }
+                }
+                sheets.push(Sheet { id: sheet.id.literal, rows });
+            }
+        }
+        let mut workbook = Self { url: t.url.literal, sheets, names: t.names.into_vec(), diagnostics, functions, settings,
+            source: None, calculation: Calculation::default() };
+        workbook.calculate();
+        Some(workbook)
+    }
 
-                },
-                Node::IntLit(t) => { 
+    /// Reads cells past the end of a sheet's rows, and sheets the payload does not have,
+    /// from `source`; every cell is evaluated again with it.
+    pub fn with_source(mut self, source: impl CellSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self.calculate();
+        self
+    }
 
-                },
-                Node::StringLit(t) => { 
+    /// Re-evaluates every cell from scratch.
+    pub fn calculate(&mut self) {
+        self.calculate_on(thread::available_parallelism().map_or(1, |n| n.get()));
+    }
 
-                },
-                Node::BoolLit(t) => { 
+    fn calculate_on(&mut self, cpus: usize) {
+        let cells: Vec<Addr> = self.sheets.iter().enumerate().flat_map(|(s, sheet)| {
+            sheet.rows.iter().enumerate().flat_map(move |(r, row)| (0..row.len()).map(move |c| (s, r, c)))
+        }).collect();
+        let rows = self.rows();
+        let mut interp = self.interpreter(&rows, Calculation::default());
+        let values = interp.settle_parallel(cells, cpus);
+        self.calculation = interp.into_calculation();
+        self.store(values);
+    }
 
-                },
-                _=> println!("{color_red}nothing")
-            }
+    /// Replaces what is written in one cell, growing the sheet if needed, and re-evaluates
+    /// only the cells that read it, directly or not. Returns every cell whose value changed,
+    /// or `None` for an unknown sheet, a bad address or a formula that does not parse.
+    /// Cells on a reference cycle may report it at a different cell than `calculate` would.
+    pub fn set_cell(&mut self, id: &str, address: &str, input: &str) -> Option<Vec<Change>> {
+        let sheet = self.sheets.iter().position(|s| s.id.eq_ignore_ascii_case(id))?;
+        let at = CellRef::parse(address)?;
+        let (input, mut node) = parse_input(input)?;
+        let bound: Vec<String> = self.names.iter().map(|n| n.token.literal.clone()).collect();
+        let mut problems: Vec<String> = Vec::new();
+        check(&mut node, &self.functions, self.settings.coercion, &bound, &mut problems);
+        let location = format!("{}!{}", self.sheets[sheet].id, CellRef { row_abs: false, col_abs: false, ..at });
+        self.diagnostics.retain(|d| d.location != location);
+        self.diagnostics.extend(problems.into_iter().map(|message| Diagnostic { location: location.clone(), message }));
+
+        let rows = &mut self.sheets[sheet].rows;
+        if rows.len() <= at.row {
+            rows.resize_with(at.row + 1, Vec::new);
         }
-        Box::new(None)
+        let row = &mut rows[at.row];
+        let mut cells: Vec<Addr> = (row.len()..at.col).map(|col| (sheet, at.row, col)).collect();
+        if row.len() <= at.col {
+            row.resize_with(at.col + 1, Cell::blank);
+        }
+        row[at.col].input = input;
+        row[at.col].program = Compiler::new(&self.names, &self.functions).compile(&node);
+        row[at.col].node = node;
+        cells.push((sheet, at.row, at.col));
+        // An array spilling over the cell now finds it in the way.
+        cells.extend(self.calculation.spills.get(&(sheet, at.row, at.col)).copied());
+        Some(self.recalculate(cells))
     }
 
+    /// Tells the workbook the cell source now holds a different value at `address` on sheet `id`,
+    /// re-evaluating the cells that read it. Returns every cell whose value changed.
+    pub fn refresh(&mut self, id: &str, address: &str) -> Option<Vec<Change>> {
+        let at = CellRef::parse(address)?;
+        let sheet = match self.sheets.iter().position(|s| s.id.eq_ignore_ascii_case(id)) {
+            Some(sheet) => sheet,
+            None => match self.calculation.external.iter().position(|s| s.eq_ignore_ascii_case(id)) {
+                Some(index) => self.sheets.len() + index,
+                // No formula has read from that sheet yet.
+                None => return Some(Vec::new()),
+            },
+        };
+        Some(self.recalculate(vec![(sheet, at.row, at.col)]))
+    }
 
-    pub fn interpreter(&self, node:&[Node])-> Vec<Mem>{
-        let mut mem:Vec<Mem> = Vec::new();
-        for value in node {
-            match value {
-                Node::CellSequence(t) => {
-                    let a= self.eval_expr(&t.values);
-                    mem.push(a);
-                },
-                _ => println!("{color_red}nothing")
-            }
+    /// Evaluates `cells` and every cell that read them, keeping what is still valid
+    /// from the last calculation, and stores the new values.
+    fn recalculate(&mut self, cells: Vec<Addr>) -> Vec<Change> {
+        let calculation = std::mem::take(&mut self.calculation);
+        let rows = self.rows();
+        let mut interp = self.interpreter(&rows, calculation);
+        let values = interp.settle(cells);
+        self.calculation = interp.into_calculation();
+        self.store(values)
+    }
+
+    fn interpreter<'a>(&'a self, rows: &'a [SheetRows<'a>], calculation: Calculation) -> Interpreter<'a> {
+        let interp = Interpreter::new(rows, &self.names, &self.functions, self.settings).resume(calculation);
+        match &self.source {
+            Some(source) => interp.with_source(source.as_ref()),
+            None => interp,
         }
-        mem
     }
 
-    pub fn eval_expr(&self, node:&[Node]) -> Mem  {
-        let mut mem:Vec<String> = Vec::new();
-        let mut val:Vec<String> = Vec::new();
-        for t in node {
-            match t {
-                Node::OperationDivide(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_DIV".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationConcat(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_CONCAT".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationIf(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_IF".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationOr(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_OR".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationNot(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_NOT".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationAnd(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_AND".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationMultiply(t) => { 
-                    mem.push(t.slot.clone());val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    } 
-                    mem.push("END_MUL".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationGt(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    } 
-                    mem.push("END_GT".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationEq(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_EQ".to_string()); val.push("0000".to_string());
-                },
-                Node::OperationSum(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                    let a = self.eval_expr(&t.values);
-                    for d in a.mem {
-                        mem.push(d);
-                    }
-                    for d in a.val {
-                        val.push(d);
-                    }
-                    mem.push("END_SUM".to_string()); val.push("0000".to_string());
-                },
-                Node::Notation(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                },
-                Node::FloatLit(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                },
-                Node::IntLit(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                },
-                Node::StringLit(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                },
-                Node::BoolLit(t) => { 
-                    mem.push(t.slot.clone()); val.push(t.token.literal.clone());
-                },
-                _=> println!("DEAD END")
+    /// Writes newly evaluated values into their cells, returning those that changed.
+    fn store(&mut self, values: HashMap<Addr, Value>) -> Vec<Change> {
+        let mut values: Vec<(Addr, Value)> = values.into_iter().collect();
+        values.sort_by_key(|(at, _)| *at);
+        let mut changes: Vec<Change> = Vec::new();
+        for ((s, r, c), value) in values {
+            let Some(cell) = self.sheets.get_mut(s).and_then(|sheet| sheet.rows.get_mut(r)).and_then(|row| row.get_mut(c)) else { continue };
+            let old = std::mem::replace(&mut cell.value, value.clone());
+            if ResultsCell::from(old) != ResultsCell::from(value.clone()) {
+                let at = CellRef { row: r, col: c, row_abs: false, col_abs: false };
+                changes.push(Change { sheet: self.sheets[s].id.clone(), cell: at, value });
             }
         }
+        changes
+    }
+
+    /// The input rows of every sheet, as the interpreter reads them.
+    fn rows(&self) -> Vec<SheetRows<'_>> {
+        self.sheets.iter().map(|s| SheetRows {
+            id: s.id.clone(),
+            rows: s.rows.iter().map(|r| r.iter().collect()).collect(),
+        }).collect()
+    }
+
+    pub fn sheets(&self) -> &[Sheet] {
+        &self.sheets
+    }
+
+    /// The workbook-level names from the payload's `names` object.
+    pub fn names(&self) -> &[NameNode] {
+        &self.names
+    }
+
+    pub fn sheet(&self, id: &str) -> Option<&Sheet> {
+        self.sheets.iter().find(|s| s.id.eq_ignore_ascii_case(id))
+    }
+
+    /// The cell at `address` on sheet `id`, e.g. `workbook.cell("sheet-2", "B3")`.
+    pub fn cell(&self, id: &str, address: &str) -> Option<&Cell> {
+        self.sheet(id)?.cell(address)
+    }
+
+    /// The computed value at `address` on sheet `id`, e.g. `workbook.value("sheet-2", "B3")`.
+    pub fn value(&self, id: &str, address: &str) -> Option<&Value> {
+        self.cell(id, address).map(|c| &c.value)
+    }
+}
 
-        let values = Mem::new(mem, val); 
-        values
+impl Serialize for Sheet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let data: Vec<Vec<ResultsCell>> = self.rows.iter()
+            .map(|r| r.iter().map(|c| ResultsCell::from(c.value.clone())).collect()).collect();
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("id", &self.id)?;
+        map.serialize_entry("data", &data)?;
+        map.end()
+    }
+}
+
+/// The submission body: `{"results": [{"id": ..., "data": [[...]]}]}`.
+impl Serialize for Workbook {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("results", &self.sheets)?;
+        map.end()
     }
 }
 
@@ -1574,11 +3742,6 @@ pub struct Parser {
     tokens: Vec<Token>,
     offset: usize,
     curr_token: Token,
-    slot: usize,
-    chars: Vec<char>,
-    tiny_chars: Vec<char>,
-    stack_slot: usize,
-    flag: bool,
 }
 
 impl Parser {
@@ -1589,11 +3752,6 @@ impl Parser {
             tokens: tokens,
             offset: 0,
             curr_token: Token::new(TokenKind::Start, "".to_string()),
-            slot: 0,
-            chars: "ABCDEFGHIJKLMNOPQRSTUVWXYZ".chars().collect(),
-            tiny_chars: "abcdefghijklmnopqrstuvwxyz".chars().collect(),
-            stack_slot:1,
-            flag: false,
         }
     }
 
@@ -1606,465 +3764,774 @@ impl Parser {
     fn expect(&mut self, kind: TokenKind) -> Option<Token> {
         if self.current() == kind {
             self.offset += 1;
-            return Some(self.curr_token.clone());
+            Some(self.curr_token.clone())
         }
         else{
-            println!("{color_red}expected {:?}, got {:?}", kind, self.curr_token);
             None
         }
     }
 
+    /// Parses a single `=formula` lexed by `Operation`, failing unless every token is used.
+    pub fn parse_formula(&mut self) -> Option<Node> {
+        self.tokens.push(Token::new(TokenKind::Eof, "".to_string()));
+        self.expect(TokenKind::Assign)?;
+        let node = self.parse_operation()?;
+        self.expect(TokenKind::Eof)?;
+        Some(node)
+    }
+
     pub fn parse_all(&mut self) -> Option<Node>  {
-        self.expect(TokenKind::OP_Brace_o);
-        self.expect(TokenKind::String);
-        self.expect(TokenKind::Punc);
-        let url =  self.expect(TokenKind::String).unwrap();
-        self.expect(TokenKind::Comma);
-        let sheets = self.expect(TokenKind::String).unwrap();
-        self.expect(TokenKind::Punc);
+        let mut url = Token::new(TokenKind::String, "".to_string());
+        let mut names: Vec<NameNode> = vec![];
         let mut args: Vec<Node> = vec![];
-        while self.current() != TokenKind::Eof {
-            args.push(self.parse_all_sheets().unwrap());
-            self.expect(TokenKind::OP_Brace_c);
+        self.expect(TokenKind::OP_Brace_o)?;
+        while self.current() == TokenKind::String {
+            let key = self.expect(TokenKind::String)?;
+            self.expect(TokenKind::Punc)?;
+            match key.literal.as_str() {
+                "submissionUrl" => { url = self.expect(TokenKind::String)?; },
+                "names" => { names = self.parse_names()?; },
+                "sheets" => { args.push(self.parse_all_sheets()?); },
+                _ => return None,
+            }
+            if self.current() == TokenKind::Comma {
+                self.expect(TokenKind::Comma);
+            }
         }
+        self.expect(TokenKind::OP_Brace_c)?;
+        Some(Evaluator(EvaluatorNode { url, names: names.into_boxed_slice(), values: args.into_boxed_slice(), }))
+    }
 
-        println!("Parser reached EOF");
-        return Some(Evaluator(EvaluatorNode { url: url, values: args.into_boxed_slice(), }))
+    /// The payload's `names` object; `None` if a definition does not parse.
+    fn parse_names(&mut self) -> Option<Vec<NameNode>> {
+        let mut names: Vec<NameNode> = vec![];
+        self.expect(TokenKind::OP_Brace_o)?;
+        while self.current() == TokenKind::String {
+            let token = self.expect(TokenKind::String)?;
+            self.expect(TokenKind::Punc)?;
+            let value = if self.current() == TokenKind::Assign {
+                self.expect(TokenKind::Assign)?;
+                let value = self.parse_operation()?;
+                self.expect(TokenKind::Eoo)?;
+                value
+            } else if self.current() == TokenKind::String && reference(&self.tokens[self.offset].literal).is_some() {
+                let val = self.expect(TokenKind::String)?;
+                reference(&val.literal)?
+            } else {
+                self.parse_operation()?
+            };
+            names.push(NameNode { token, value: Box::new(value) });
+            if self.current() == TokenKind::Comma {
+                self.expect(TokenKind::Comma);
+            }
+        }
+        self.expect(TokenKind::OP_Brace_c)?;
+        Some(names)
     }
 
     fn parse_all_sheets(&mut self) -> Option<Node> {
         let mut args: Vec<Node> = vec![];
 
-        self.expect(TokenKind::Arr_o);
+        self.expect(TokenKind::Arr_o)?;
 
         if self.current() != TokenKind::Arr_c { 
-            args.push(self.parse_sheet().unwrap());
+            args.push(self.parse_sheet()?);
             while self.current() != TokenKind::Arr_c {
-                self.expect(TokenKind::Comma);
-                if self.current() == TokenKind::OP_Brace_o {
-                    args.push(self.parse_sheet().unwrap());
-                }
+                self.expect(TokenKind::Comma)?;
+                args.push(self.parse_sheet()?);
             }
         }
 
-        self.expect(TokenKind::Arr_c);
-        return Some(Spreadsheet(SpreadsheetNode {values: args.into_boxed_slice(), }))
+        self.expect(TokenKind::Arr_c)?;
+        Some(Spreadsheet(SpreadsheetNode {values: args.into_boxed_slice(), }))
     }
 
     fn parse_sheet(&mut self) -> Option<Node> {
         let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::OP_Brace_o);
-        self.expect(TokenKind::String);
-        self.expect(TokenKind::Punc);
-        let sheet_id = self.expect(TokenKind::String).unwrap();
-        self.expect(TokenKind::Comma);
-        self.expect(TokenKind::String);
-        self.expect(TokenKind::Punc);
-        args.push(self.parse_sheet_data().unwrap());     
-
-        self.expect(TokenKind::OP_Brace_c);
-        return Some(Sheet(SheetNode { id: sheet_id, values: args.into_boxed_slice(), }))
+        self.expect(TokenKind::OP_Brace_o)?;
+        self.expect(TokenKind::String)?;
+        self.expect(TokenKind::Punc)?;
+        let sheet_id = self.expect(TokenKind::String)?;
+        self.expect(TokenKind::Comma)?;
+        self.expect(TokenKind::String)?;
+        self.expect(TokenKind::Punc)?;
+        args.push(self.parse_sheet_data()?);     
+
+        self.expect(TokenKind::OP_Brace_c)?;
+        Some(Node::Sheet(SheetNode { id: sheet_id, values: args.into_boxed_slice(), }))
     }
 
     fn parse_sheet_data(&mut self) -> Option<Node> {
-        self.expect(TokenKind::Arr_o);
+        self.expect(TokenKind::Arr_o)?;
         let mut args: Vec<Node> = vec![];
-        if self.current() == TokenKind::Arr_o {
-            args.push(self.parse_expr_sequence().unwrap());     
-        }
-
         if self.current() != TokenKind::Arr_c { 
+            args.push(self.parse_expr_sequence()?);     
             while self.current() != TokenKind::Arr_c {
-                self.expect(TokenKind::Comma);
-                if self.current() == TokenKind::Arr_o {
-                    // self.expect(TokenKind::Arr_o);
-                    self.stack_slot+=1;
-                    args.push(self.parse_expr_sequence().unwrap());     
+                self.expect(TokenKind::Comma)?;
+                args.push(self.parse_expr_sequence()?);     
+            }
+        }
+
+        self.expect(TokenKind::Arr_c)?;
+        Some(DataCells(DataNode {values: args.into_boxed_slice(), }))
+    }
+
+    fn parse_expr_sequence(&mut self) -> Option<Node> {
+        self.expect(TokenKind::Arr_o)?;
+        let mut args: Vec<Node> = vec![];
+        let mut sources: Vec<String> = vec![];
+        if self.current() != TokenKind::Arr_c {
+            loop {
+                let (cell, source) = self.parse_cell()?;
+                args.push(cell);
+                sources.push(source);
+                if self.current() == TokenKind::Arr_c {
+                    break;
                 }
+                self.expect(TokenKind::Comma)?;
             }
         }
 
-        self.expect(TokenKind::Arr_c); self.reset_stack_slot();
-        return Some(DataCells(DataNode {values: args.into_boxed_slice(), mem: None}))
+        self.expect(TokenKind::Arr_c)?;
+        Some(CellSequence(CellSequenceNode {values: args.into_boxed_slice(), sources, }))
     }
 
-    fn peek2(&mut self, type0: TokenKind, type1: TokenKind) -> Option<bool> {
-        let token0 = self.tokens.get(self.offset).unwrap().clone();
-        let next = self.offset + 1;
-        if next > self.tokens.len() {
-            return None;
-        } else {
-            let token1 = self.tokens.get(self.offset+1).unwrap().clone();
-            return Some((token0.kind == type0) && (token1.kind == type1));
+    /// One cell of a row and its text. A formula that fails to parse, or does not use every
+    /// token up to its `Eoo`, is an `Invalid` cell; `None` is left for malformed JSON.
+    fn parse_cell(&mut self) -> Option<(Node, String)> {
+        let start = self.offset;
+        if !matches!(self.current(), TokenKind::Assign | TokenKind::Eoo) {
+            let node = self.parse_operation()?;
+            return Some((node, render(&self.tokens[start..self.offset])));
+        }
+        if self.expect(TokenKind::Assign).is_some() {
+            if let Some(node) = self.parse_operation() {
+                if self.current() == TokenKind::Eoo {
+                    let source = render(&self.tokens[start..self.offset]);
+                    self.offset += 1;
+                    return Some((node, source));
+                }
+            }
+        }
+        while !matches!(self.current(), TokenKind::Eoo | TokenKind::Eof) {
+            self.offset += 1;
         }
+        let span = start..self.offset;
+        let source = self.expect(TokenKind::Eoo)?.literal;
+        Some((Node::Invalid(InvalidNode { message: "formula does not parse".to_string(), span }), source))
     }
 
-    fn reset(&mut self) {
-        self.slot = 0;
+    fn parse_operation(&mut self) -> Option<Node> {
+        let mut lhs = self.parse_term()?;
+        while self.current() == TokenKind::Plus || self.current() == TokenKind::Minus {
+            let kind = self.current();
+            let op = self.expect(kind).unwrap();
+            let rhs = self.parse_term()?;
+            lhs = Node::Binary(BinaryNode { token: op, lhs: Box::new(lhs), rhs: Box::new(rhs) });
+        }
+        Some(lhs)
     }
 
-    fn reset_stack_slot(&mut self) {
-        self.stack_slot = 1;
+    fn parse_term(&mut self) -> Option<Node> {
+        let mut lhs = self.parse_call()?;
+        while self.current() == TokenKind::Star || self.current() == TokenKind::Slash {
+            let kind = self.current();
+            let op = self.expect(kind).unwrap();
+            let rhs = self.parse_call()?;
+            lhs = Node::Binary(BinaryNode { token: op, lhs: Box::new(lhs), rhs: Box::new(rhs) });
+        }
+        Some(lhs)
     }
 
-    fn parse_expr_sequence(&mut self) -> Option<Node> {
-        self.expect(TokenKind::Arr_o);
-        let mut args: Vec<Node> = vec![];
-        if self.current() == TokenKind::Assign {
-            self.expect(TokenKind::Assign);
-            args.push(self.parse_operation().unwrap());     
-        } else if self.current() != TokenKind::Arr_c {
-            args.push(self.parse_operation().unwrap());
+    fn parse_call(&mut self) -> Option<Node> {
+        let mut callee = self.parse_primary()?;
+        while self.current() == TokenKind::Op_Paren_o {
+            let args = self.parse_args()?;
+            callee = Node::Apply(ApplyNode { callee: Box::new(callee), values: args.into_boxed_slice() });
         }
+        Some(callee)
+    }
 
-        if self.current() != TokenKind::Arr_c { 
-            while self.current() != TokenKind::Arr_c {
-                self.expect(TokenKind::Comma);
-                if self.current() == TokenKind::Assign {
-                    self.expect(TokenKind::Assign);
-                    args.push(self.parse_operation().unwrap());     
-                } else {
-                    args.push(self.parse_operation().unwrap());
-                }
-            }
+    fn parse_reference(&mut self, sheet: Option<Token>) -> Option<Node> {
+        let val = self.expect(TokenKind::Notation)?;
+        let reference = CellRef::parse(&val.literal)?;
+        if self.current() == TokenKind::Punc {
+            self.expect(TokenKind::Punc);
+            let to = self.expect(TokenKind::Notation)?;
+            return Some(Node::Range(RangeNode { from: reference, to: CellRef::parse(&to.literal)?, sheet }))
         }
+        if self.current() == TokenKind::Hash {
+            self.expect(TokenKind::Hash);
+            return Some(Node::SpillRef(NotationNode {token: val, reference, sheet}))
+        }
+        Some(Notation(NotationNode {token: val, reference, sheet}))
+    }
 
-        self.expect(TokenKind::Arr_c); self.reset();
-        return Some(CellSequence(CellSequenceNode {values: args.into_boxed_slice(), mem: None}))
+    fn parse_args(&mut self) -> Option<Vec<Node>> {
+        let mut args: Vec<Node> = vec![];
+        self.expect(TokenKind::Op_Paren_o);
+        if self.current() != TokenKind::Op_Paren_c { 
+            args.push(self.parse_operation()?);
+            while self.current() != TokenKind::Op_Paren_c {
+                self.expect(TokenKind::Comma)?;
+                args.push(self.parse_operation()?);
+            }
+        }
+        self.expect(TokenKind::Op_Paren_c)?;
+        Some(args)
     }
 
-    fn parse_operation(&mut self) -> Option<Node> {
+    fn parse_primary(&mut self) -> Option<Node> {
         match self.current() {
-            TokenKind::Operation_Eq => { return self.parse_eq();},
-            TokenKind::Operation_Gt => { return self.parse_gt();},
-            TokenKind::Operation_If => { return self.parse_if();},
-            TokenKind::Operation_And => { return self.parse_and();},
-            TokenKind::Operation_Sum => { return self.parse_sum();},
-            TokenKind::Operation_Or => { return self.parse_or();},
-            TokenKind::Operation_Concat => { return self.parse_concat();},
-            TokenKind::Operation_Multiply => { return self.parse_multilply();},
-            TokenKind::Operation_Divide => { return self.parse_divide();},
-            TokenKind::Operation_Not => { return self.parse_not();},
+            TokenKind::Operation_Let => self.parse_let(),
+            TokenKind::Operation_Lambda => self.parse_lambda(),
+            TokenKind::Op_Paren_o => {
+                self.expect(TokenKind::Op_Paren_o);
+                let inner = self.parse_operation()?;
+                self.expect(TokenKind::Op_Paren_c)?;
+                Some(inner)
+            },
+            TokenKind::Identifier => {
+                let val = self.expect(TokenKind::Identifier).unwrap();
+                if self.current() == TokenKind::Bang {
+                    self.expect(TokenKind::Bang);
+                    return self.parse_reference(Some(val))
+                }
+                if self.current() == TokenKind::Op_Paren_o {
+                    return self.parse_function(val)
+                }
+                Some(Node::Identifier(LitNode { token: val }))
+            },
             TokenKind::Int => {
-                self.slot+=1;
                 let val = self.expect(TokenKind::Int).unwrap(); 
-                return Some(IntLit(LitNode { token: val, slot: self.convertation(), }))
+                Some(IntLit(LitNode { token: val }))
             },
             TokenKind::String => {
-                self.slot+=1;
                 let val = self.expect(TokenKind::String).unwrap();  
-                return Some(StringLit(LitNode { token: val, slot: self.convertation(),  }))  
+                Some(StringLit(LitNode { token: val }))  
             },
             TokenKind::Double => {
-                self.slot+=1;
                 let val = self.expect(TokenKind::Double).unwrap(); 
-                return Some(FloatLit(LitNode { token: val, slot: self.convertation(),  }))
+                Some(FloatLit(LitNode { token: val }))
             },
             TokenKind::Bool => {
-                self.slot+=1;
                 let val = self.expect(TokenKind::Bool).unwrap(); 
-                return Some(BoolLit(LitNode {token: val, slot: self.convertation(), }))
-            },
-            TokenKind::Notation => {
-                self.slot+=1;
-                let val = self.expect(TokenKind::Notation).unwrap(); 
-                let notation_slot = val.literal.clone();
-                return Some(Notation(NotationNode {token: val, slot: self.convertation(), }))
+                Some(BoolLit(LitNode {token: val }))
             },
-            _ => {
-                println!("{color_red}bad node {:?}", self.current()); 
-                return None
-            }
+            TokenKind::Notation => self.parse_reference(None),
+            _ => None,
         }   
     }
 
-    fn convertation(&mut self) -> String {
-        if self.flag == true {
-            let s: String = self.get_tiny_char().to_string();
-            let s1: String = self.stack_slot.to_string();
-            let ss = s+&s1;
-            return ss
-        } else {
-            let s: String = self.get_char().to_string();
-            let s1: String = self.stack_slot.to_string();
-            let ss = s+&s1;
-            return ss
+    fn parse_function(&mut self, name: Token) -> Option<Node> {
+        let start = self.offset - 1;
+        let args = self.parse_args()?;
+        Some(Node::Call(CallNode { name, args: args.into_boxed_slice(), span: start..self.offset }))
+    }
+
+    fn parse_let(&mut self) -> Option<Node> {
+        let token = self.expect(TokenKind::Operation_Let).unwrap();
+        let mut args = self.parse_args()?;
+        if args.len() < 3 || args.len() % 2 == 0 {
+            return None
+        }
+        let body = args.pop().unwrap();
+        let mut names: Vec<Token> = vec![];
+        let mut values: Vec<Node> = vec![];
+        let mut iter = args.into_iter();
+        while let Some(name) = iter.next() {
+            match name {
+                Node::Identifier(t) => names.push(t.token),
+                _ => return None,
+            }
+            values.push(iter.next().unwrap());
         }
+        Some(Node::OperationLet(LetNode { names, values: values.into_boxed_slice(), body: Box::new(body), token }))
     }
 
-    fn get_char(&mut self) -> char {
-        let a = self.slot -1;
-        *self.chars.get(a).unwrap()
+    fn parse_lambda(&mut self) -> Option<Node> {
+        let token = self.expect(TokenKind::Operation_Lambda).unwrap();
+        let mut args = self.parse_args()?;
+        let body = args.pop()?;
+        let mut params: Vec<Token> = vec![];
+        for param in args {
+            match param {
+                Node::Identifier(t) => params.push(t.token),
+                _ => return None,
+            }
+        }
+        Some(Node::OperationLambda(LambdaNode { params, body: Arc::new(body), token }))
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn get_tiny_char(&mut self) -> char {
-        let a = self.slot -1;
-        *self.tiny_chars.get(a).unwrap()
+    fn workbook(json: &str) -> Workbook {
+        Workbook::from_json(json, Registry::new(), Settings::default()).unwrap()
     }
 
-    fn parse_eq(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        self.flag = true;
-        // self.reset();
-        let sum = self.expect(TokenKind::Operation_Eq).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
+    fn values(workbook: &Workbook) -> String {
+        serde_json::to_string(workbook).unwrap()
+    }
+
+    /// Edits one cell with `set_cell`, then checks every value against a workbook built from
+    /// the edited payload from scratch.
+    fn assert_edit_matches_full(before: &str, after: &str, id: &str, address: &str, input: &str) -> Workbook {
+        let mut edited = workbook(before);
+        let changes = edited.set_cell(id, address, input).unwrap();
+        assert!(!changes.is_empty());
+        assert_eq!(values(&edited), values(&workbook(after)));
+        edited
+    }
+
+    #[test]
+    fn set_cell_takes_spilled_cells_from_later_anchor() {
+        let before = r#"{"submissionUrl": "x", "sheets": [{"id": "s2", "data": [["=A2", "=SEQUENCE(1,1)"], ["=SEQUENCE(1,2)", ""]]}]}"#;
+        let after = r#"{"submissionUrl": "x", "sheets": [{"id": "s2", "data": [["=A2", "=SEQUENCE(2)"], ["=SEQUENCE(1,2)", ""]]}]}"#;
+        let edited = assert_edit_matches_full(before, after, "s2", "B1", "=SEQUENCE(2)");
+        assert!(matches!(edited.value("s2", "A2"), Some(Value::Error(e)) if e.kind == ErrorKind::Spill));
+        assert!(matches!(edited.value("s2", "A1"), Some(Value::Error(e)) if e.kind == ErrorKind::Spill));
+        assert!(matches!(edited.value("s2", "B2"), Some(Value::Int(2))));
+    }
+
+    #[test]
+    fn set_cell_spill_matches_full_evaluation() {
+        let before = r#"{"submissionUrl": "x", "sheets": [{"id": "s2", "data": [
+            [1, "=C1", "=B3", "=C3"], ["", "=C3", "=A1", "=C2"], ["=B3", "=SEQUENCE(1,2)", "", ""], ["", "=C3", "", ""]]}]}"#;
+        let after = before.replacen(r#""=C3", "=A1""#, r#""=C3", "=SEQUENCE(2)""#, 1);
+        let edited = assert_edit_matches_full(before, &after, "s2", "C2", "=SEQUENCE(2)");
+        assert!(matches!(edited.value("s2", "B3"), Some(Value::Error(e)) if e.kind == ErrorKind::Spill));
+        assert!(matches!(edited.value("s2", "C3"), Some(Value::Int(2))));
+    }
+
+    #[test]
+    fn set_cell_into_spilled_cell_blocks_the_array() {
+        let before = r#"{"submissionUrl": "x", "sheets": [{"id": "s2", "data": [["=SEQUENCE(2)", "=A2"], ["", ""]]}]}"#;
+        let after = r#"{"submissionUrl": "x", "sheets": [{"id": "s2", "data": [["=SEQUENCE(2)", "=A2"], [5, ""]]}]}"#;
+        let edited = assert_edit_matches_full(before, after, "s2", "A2", "5");
+        assert!(matches!(edited.value("s2", "A1"), Some(Value::Error(e)) if e.kind == ErrorKind::Spill));
+    }
+
+    #[test]
+    fn parallel_evaluation_is_repeatable_and_matches_recalculation() {
+        // Levels wider than MIN_LEVEL_CHUNK, so they are shared out between threads.
+        let payload = |seed: i64| {
+            let mut a = vec![format!(r#"[{}, "=SUM(b!C1:C3)+A1", "=b!D2", "=SUM(B2:B600)"]"#, seed)];
+            let mut b = vec![r#"["=SEQUENCE(a!A1)", "", "=SEQUENCE(3)", "=SEQUENCE(a!A1)"]"#.to_string()];
+            for r in 2..=600 {
+                a.push(format!(r#"["=$A$1*{0}", "=b!A{0}+A{0}"]"#, r));
+                b.push(format!(r#"["=a!A{0}*2", "=A{0}+a!B{0}", "", ""]"#, r));
             }
+            format!(r#"{{"submissionUrl": "x", "sheets": [{{"id": "a", "data": [{}]}}, {{"id": "b", "data": [{}]}}]}}"#,
+                a.join(","), b.join(","))
+        };
+        let (before, after) = (payload(1), payload(2));
+        let edited = assert_edit_matches_full(&before, &after, "a", "A1", "2");
+        let mut book = workbook(&after);
+        for cpus in [1, 4, 16, 4] {
+            book.calculate_on(cpus);
+            assert_eq!(values(&book), values(&edited), "on {} threads", cpus);
         }
-        self.flag = false;
-        // let term_back = self.slot;
-        self.slot = term;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationEq(EqNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
+        let value = |id: &str, address: &str| edited.value(id, address).unwrap().to_string();
+        assert_eq!(value("b", "D2"), "2");
+        assert_eq!(value("a", "C1"), "2");
+        assert_eq!(value("a", "B1"), "8");
+        assert_eq!(value("a", "D1"), (3 * 2 * (2..=600).sum::<i64>()).to_string());
+        assert_eq!(value("b", "B600"), (5 * 2 * 600).to_string());
     }
 
-    fn parse_gt(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Gt).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    /// A cell source the test can still change after handing it to a workbook.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<std::sync::Mutex<HashMap<String, Value>>>);
+
+    impl Shared {
+        fn set(&self, key: &str, value: i64) {
+            self.0.lock().unwrap().insert(key.to_string(), Value::Int(value));
         }
-        self.flag = false;
-        let term_back = self.slot;
-        self.slot = term;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationGt(GtNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
     }
 
-    fn parse_if(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_If).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    impl CellSource for Shared {
+        fn value(&self, sheet: &str, cell: CellRef) -> Option<Value> {
+            self.0.lock().unwrap().value(sheet, cell)
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationIf(IfNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        return Some(ret)
     }
 
-    fn parse_and(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_And).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
-        }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationAnd(AndNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
-    }
-    fn parse_not(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Not).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    #[test]
+    fn refresh_re_evaluates_what_read_the_changed_source_cell() {
+        let source = Shared::default();
+        source.set("s!A3", 10);
+        source.set("ext!A1", 5);
+        source.set("ext!A2", 6);
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [
+            [1, "=A1+A3", "=SUM(ext!A1:A2)", "=C1*2"], [2, "=B1"]]}]}"#);
+        assert_eq!(book.value("s", "B1").map(|v| v.to_string()), Some("1".to_string()));
+        let mut book = book.with_source(source.clone());
+        let changed = |changes: Option<Vec<Change>>| -> Vec<String> {
+            changes.unwrap().iter().map(|c| format!("{}!{}={}", c.sheet, c.cell, c.value)).collect()
+        };
+        // Past the end of a payload sheet, and on a sheet only the source has.
+        assert_eq!(book.value("s", "B1").map(|v| v.to_string()), Some("11".to_string()));
+        assert_eq!(book.value("s", "D1").map(|v| v.to_string()), Some("22".to_string()));
+
+        source.set("ext!A1", 50);
+        assert_eq!(changed(book.refresh("ext", "A1")), ["s!C1=56", "s!D1=112"]);
+        source.set("s!A3", 20);
+        assert_eq!(changed(book.refresh("s", "A3")), ["s!B1=21", "s!B2=21"]);
+        source.set("ext!A9", 1);
+        assert!(changed(book.refresh("ext", "A9")).is_empty());
+        assert!(changed(book.refresh("nowhere", "A1")).is_empty());
+        source.set("s!A3", 20);
+        assert!(changed(book.refresh("s", "A3")).is_empty());
+        assert!(book.refresh("ext", "A0").is_none());
+    }
+
+    fn formula(formula: &str) -> Value {
+        evaluate_formula(formula, &HashMap::<String, Value>::new())
+    }
+
+    /// Evaluates `formula` with `flows` in A1, B1, C1 and so on.
+    fn with_row(formula: &str, flows: &[i64]) -> Value {
+        let cells: HashMap<String, Value> = flows.iter().enumerate()
+            .map(|(c, n)| (CellRef { row: 0, col: c, row_abs: false, col_abs: false }.to_string(), Value::Int(*n))).collect();
+        evaluate_formula(formula, &cells)
+    }
+
+    fn assert_close(formula_text: &str, expected: f64) {
+        match formula(formula_text) {
+            Value::Number(n) => assert!((n - expected).abs() < 1e-6 * expected.abs().max(1.0), "{} = {}, expected {}", formula_text, n, expected),
+            v => panic!("{} = {}, expected {}", formula_text, v, expected),
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationNot(NotNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(), mem: None});
-        // self.slot = term_back;
-        return Some(ret)
     }
 
-    fn parse_or(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Or).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    #[test]
+    fn financial_functions_match_reference_values() {
+        assert_close("=PMT(0.05/12,360,200000)", -1073.643246);
+        assert_close("=PMT(0,10,1000)", -100.0);
+        assert_close("=PV(0.08/12,240,500)", -59777.145851);
+        assert_close("=FV(0.06/12,10,-200,-500,1)", 2581.403374);
+        assert_close("=NPV(0.1,-10000,3000,4200,6800)", 1188.443412);
+        let irr_of = |formula: &str, flows: &[i64]| match with_row(formula, flows) {
+            Value::Number(n) => n,
+            v => panic!("{} = {}", formula, v),
+        };
+        let flows = [-70000, 12000, 15000, 18000, 21000, 26000];
+        assert!((irr_of("=IRR(A1:F1)", &flows) - 0.086630948).abs() < 1e-8);
+        assert!((irr_of("=IRR(A1:E1)", &flows) + 0.021244848).abs() < 1e-8);
+        // The guess only picks where the search starts; it is not another cash flow.
+        assert!((irr_of("=IRR(A1:E1, -0.1)", &flows) + 0.021244848).abs() < 1e-8);
+        assert!((irr_of("=IRR(A1:C1, 0.5)", &[-100, 60, 60]) - 0.130662386).abs() < 1e-8);
+        assert!(matches!(formula("=IRR(-100, 60, 60)"), Value::Error(e) if e.kind == ErrorKind::Value));
+        // Any non-zero `type` means payments at the start of each period.
+        assert_close("=PMT(0.05,10,1000,0,2)", -123.337690);
+        assert_close("=PMT(0.05,10,1000,0,-1)", -123.337690);
+        assert_close("=FV(0.06/12,10,-200,-500,0.5)", 2581.403374);
+        assert_close("=RATE(48,-200,8000)", 0.007701472);
+        assert_close("=NPER(0.12/12,-100,-1000,10000,1)", 59.673866);
+        assert_close("=NPER(0.01,-100,-1000)", -9.578594);
+    }
+
+    #[test]
+    fn irr_and_rate_report_num_when_they_do_not_converge() {
+        for (text, flows) in [("=IRR(A1:C1)", &[100, 200, 300][..]), ("=IRR(A1:B1)", &[-100, -200]), ("=IRR(100, 200)", &[]), ("=RATE(10,100,1000)", &[])] {
+            assert!(matches!(with_row(text, flows), Value::Error(e) if e.kind == ErrorKind::Num), "{} = {}", text, with_row(text, flows));
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationOr(OrNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(), mem: None});
-        // self.slot = term_back;
-        return Some(ret)
+        assert_eq!(irr(&[1.0, 2.0], 0.1), None);
+        assert_eq!(rate(10.0, 100.0, 1000.0, 0.0, 0.0, 0.1), None);
     }
 
-    fn parse_divide(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Divide).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    #[test]
+    fn js_number_matches_ecmascript_to_string() {
+        // Pairs of a number and what `Number.prototype.toString` gives for it.
+        let table: [(f64, &str); 24] = [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (1.0, "1"),
+            (100.0, "100"),
+            (-2.75, "-2.75"),
+            (0.1, "0.1"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1.0 / 3.0, "0.3333333333333333"),
+            (4.35, "4.35"),
+            (2f64.powi(53), "9007199254740992"),
+            (1e20, "100000000000000000000"),
+            (123456789012345680000.0, "123456789012345680000"),
+            (1e21, "1e+21"),
+            (1.5e22, "1.5e+22"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (0.000001, "0.000001"),
+            (0.000001234, "0.000001234"),
+            (1e-7, "1e-7"),
+            (2e-7, "2e-7"),
+            (123e-20, "1.23e-18"),
+            (5e-324, "5e-324"),
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+        ];
+        for (x, expected) in table {
+            assert_eq!(js_number(x), expected, "{:e}", x);
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationDivide(DivideNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
     }
 
-    fn parse_concat(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Concat).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    fn in_mode(formula: &str, numeric: Numeric) -> String {
+        let settings = Settings { numeric, ..Settings::default() };
+        evaluate_formula_with(formula, &HashMap::<String, Value>::new(), &Registry::new(), settings).to_string()
+    }
+
+    #[test]
+    fn decimal_mode_agrees_with_float_where_float_is_exact() {
+        let decimal = Numeric::Decimal { scale: 10 };
+        let exact = [
+            ("=SUM(1.5,2.25,-0.75)", "3"),
+            ("=SUM(0.5,0.25,0.125)", "0.875"),
+            ("=1.25-0.5", "0.75"),
+            ("=MULTIPLY(1.5,4,0.5)", "3"),
+            ("=MULTIPLY(2.5,2.5)", "6.25"),
+            ("=DIVIDE(10,4)", "2.5"),
+            ("=DIVIDE(1,8)", "0.125"),
+            ("=DIVIDE(7.5,2.5)", "3"),
+            ("=EQ(SUM(0.25,0.25),0.5)", "true"),
+            ("=NE(0.75,0.5)", "true"),
+            ("=GT(0.75,0.5)", "true"),
+            ("=LT(-1.5,-1.25)", "true"),
+            ("=GTE(2.5,2.5)", "true"),
+            ("=LTE(2.5,2.25)", "false"),
+        ];
+        for (text, expected) in exact {
+            assert_eq!(in_mode(text, Numeric::Float), expected, "{} in float mode", text);
+            assert_eq!(in_mode(text, decimal), expected, "{} in decimal mode", text);
+        }
+        for (a, b) in [(1.5, 0.25), (-3.0, 0.5), (1024.0, 0.125)] {
+            assert_eq!(decimal.sum(&[a, b]), Numeric::Float.sum(&[a, b]));
+            assert_eq!(decimal.difference(a, b), Numeric::Float.difference(a, b));
+            assert_eq!(decimal.product(&[a, b]), Numeric::Float.product(&[a, b]));
+            assert_eq!(decimal.quotient(a, b), Numeric::Float.quotient(a, b));
+            assert_eq!(decimal.compare(a, b), Numeric::Float.compare(a, b));
         }
-        self.flag = false;
-        let term_back = self.slot;
-        self.slot = term;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationConcat(ConcatNode { values: args.into_boxed_slice(), token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
     }
 
-    fn parse_sum(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Sum).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    #[test]
+    fn decimal_mode_sums_tenths_exactly() {
+        let decimal = Numeric::Decimal { scale: 10 };
+        assert_eq!(in_mode("=SUM(0.1,0.2)", decimal), "0.3");
+        assert_eq!(in_mode("=SUM(0.1,0.2)", Numeric::Float), "0.30000000000000004");
+        assert_eq!(in_mode("=EQ(SUM(0.1,0.2),0.3)", decimal), "true");
+        assert_eq!(in_mode("=EQ(SUM(0.1,0.2),0.3)", Numeric::Float), "false");
+    }
+
+    #[test]
+    fn sequence_too_large_is_an_error_not_an_allocation() {
+        for text in ["=SUM(SEQUENCE(2000000000))", "=SEQUENCE(1,20000)", "=SEQUENCE(100000,100)", "=SEQUENCE(100000000000000000000,100000000000000000000)"] {
+            assert!(matches!(formula(text), Value::Error(e) if e.kind == ErrorKind::Num), "{} = {}", text, formula(text));
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationSum(SumNode {values: args.into_boxed_slice(),token: sum, slot: self.convertation(),mem: None});
-        // self.slot = term_back;
-        return Some(ret)
+        assert_eq!(formula("=SUM(SEQUENCE(1024,1024))").to_string(), "549756338176");
     }
 
-    fn parse_multilply(&mut self) -> Option<Node> {
-        self.slot+=1;
-        let term = self.slot;
-        // self.reset();
-        self.flag = true;
-        let sum = self.expect(TokenKind::Operation_Multiply).unwrap(); 
-        let mut args: Vec<Node> = vec![];
-        self.expect(TokenKind::Op_Paren_o);
-        if self.current() != TokenKind::Op_Paren_c { 
-            args.push(self.parse_operation().unwrap());
-            while self.current() != TokenKind::Op_Paren_c {
-                self.expect(TokenKind::Comma);
-                args.push(self.parse_operation().unwrap());
-            }
+    #[test]
+    fn malformed_name_fails_to_parse_instead_of_panicking() {
+        for names in [r#"{"X": "=SUM("}"#, r#"{"X": "=1+"}"#, r#"{"X": "=LET(a)"}"#, r#"{"X": }"#] {
+            let json = format!(r#"{{"submissionUrl": "x", "names": {}, "sheets": [{{"id": "s", "data": [["=X"]]}}]}}"#, names);
+            assert!(Workbook::from_json(&json, Registry::new(), Settings::default()).is_none(), "{}", names);
         }
-        let term_back = self.slot;
-        self.slot = term;
-        self.flag = false;
-        self.expect(TokenKind::Op_Paren_c);
-        let ret = Node::OperationMultiply(MultiplyNode {values: args.into_boxed_slice(),token: sum, slot: self.convertation(), mem: None});
-        // self.slot = term_back;
-        return Some(ret)
+        let json = r#"{"submissionUrl": "x", "names": {"X": "=SUM(1,2)"}, "sheets": [{"id": "s", "data": [["=X"]]}]}"#;
+        assert_eq!(workbook(json).value("s", "A1").map(|v| v.to_string()), Some("3".to_string()));
     }
 
+    #[test]
+    fn let_binds_in_order_and_inner_names_shadow_outer_ones() {
+        assert_eq!(formula("=LET(x, 2, y, x*3, x+y)").to_string(), "8");
+        assert_eq!(formula("=LET(x, 1, LET(x, 10, x)+x)").to_string(), "11");
+        assert_eq!(formula("=LET(x, 1, x, x+1, x*5)").to_string(), "10");
+        assert_eq!(with_row("=LET(A, 5, A+A1)", &[7]).to_string(), "12");
+        assert!(matches!(formula("=LET(x, 1, y)"), Value::Error(e) if e.kind == ErrorKind::Name));
+    }
 
-    fn parse_notation(&mut self) -> Option<Node> {
-        self.expect(TokenKind::Assign);
-        let val = self.expect(TokenKind::Notation).unwrap();
-        let notation_slot = val.literal.clone();
-        return Some(Notation(NotationNode {
-            token: val, slot: self.convertation(),
-        }))
+    #[test]
+    fn lambdas_apply_in_place_and_capture_their_scope() {
+        assert_eq!(formula("=LAMBDA(x, x+1)(2)").to_string(), "3");
+        assert_eq!(formula("=LAMBDA(x, y, x*y)(3, 4)").to_string(), "12");
+        assert_eq!(formula("=LET(k, 10, add, LAMBDA(x, x+k), LET(k, 100, add(1)))").to_string(), "11");
+        assert!(matches!(formula("=LAMBDA(x, x)(1, 2)"), Value::Error(e) if e.kind == ErrorKind::Value));
+        assert!(matches!(formula("=LET(n, 1, n(2))"), Value::Error(e) if e.kind == ErrorKind::Value));
     }
-}
 
-#[derive(Debug)]
-pub struct Mem {
-    pub mem: Vec<String>,
-    pub val: Vec<String>,
-}
+    #[test]
+    fn named_lambdas_are_shared_across_cells_and_sheets() {
+        let book = workbook(r#"{"submissionUrl": "x",
+            "names": {"DOUBLE": "=LAMBDA(x, x*2)", "FACT": "=LAMBDA(n, IF(GT(n, 1), n*FACT(n-1), 1))", "SPIN": "=LAMBDA(n, SPIN(n+1))"},
+            "sheets": [{"id": "s", "data": [[3, "=DOUBLE(A1)", "=DOUBLE(B1)+1", "=FACT(5)", "=SPIN(0)", "=DOUBLE"]]},
+                       {"id": "t", "data": [["=DOUBLE(4)"]]}]}"#);
+        let value = |id: &str, address: &str| book.value(id, address).unwrap().to_string();
+        assert_eq!(value("s", "B1"), "6");
+        assert_eq!(value("s", "C1"), "13");
+        assert_eq!(value("s", "D1"), "120");
+        assert_eq!(value("t", "A1"), "8");
+        // Each call nests one deeper, so unbounded recursion stops at MAX_CALL_DEPTH.
+        assert!(matches!(book.value("s", "E1"), Some(Value::Error(e)) if e.kind == ErrorKind::Num && e.detail.as_deref() == Some("recursion too deep")));
+        assert!(matches!(book.value("s", "F1"), Some(Value::Lambda(_))));
+    }
 
-impl Mem {
-    pub fn new(mem: Vec<String>, val: Vec<String>) -> Self {
+    #[test]
+    fn rows_and_calls_wider_than_the_alphabet_parse() {
+        let ones = vec!["1"; 30].join(",");
+        let row = (1..=30).map(|n| n.to_string()).collect::<Vec<_>>().join(",");
+        let json = format!(r#"{{"submissionUrl": "x", "sheets": [{{"id": "s", "data": [[{}], ["=SUM({})", "=SUM(A1:AD1)"]]}}]}}"#, row, ones);
+        let book = workbook(&json);
+        assert_eq!(book.value("s", "AD1").map(|v| v.to_string()), Some("30".to_string()));
+        assert_eq!(book.value("s", "A2").map(|v| v.to_string()), Some("30".to_string()));
+        assert_eq!(book.value("s", "B2").map(|v| v.to_string()), Some("465".to_string()));
+    }
 
-        Self { 
-                mem: mem,
-                val: val,
-            }
+    #[test]
+    fn floats_serialize_tagged_with_javascript_digits() {
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [[5, 5.0, "=2+3", "=2.5*2", "=1/4", -2.0, 1e21]]}]}"#);
+        let float = |digits: &str| format!(r#"{{"type":"float","value":{}}}"#, digits);
+        assert_eq!(values(&book), format!(r#"{{"results":[{{"id":"s","data":[[5,{},5,{},{},{},{}]]}}]}}"#,
+            float("5"), float("5"), float("0.25"), float("-2"), float("1e+21")));
+    }
 
+    #[test]
+    fn translate_fills_down_mixed_references() {
+        assert_eq!(translate("=$A1", "B1", "B3").as_deref(), Some("=$A3"));
+        assert_eq!(translate("=A$1", "B1", "B3").as_deref(), Some("=A$1"));
+        assert_eq!(translate("=$A$1", "B1", "B3").as_deref(), Some("=$A$1"));
+        assert_eq!(translate("=$A1+A$1+$A$1+A1", "B1", "C2").as_deref(), Some("=$A2+B$1+$A$1+B2"));
+    }
+
+    #[test]
+    fn translate_keeps_string_literals_and_rejects_what_it_cannot_lex() {
+        assert_eq!(translate(r#"=CONCAT("a b", A1, "")"#, "B1", "B2").as_deref(), Some(r#"=CONCAT("a b",A2,"")"#));
+        assert_eq!(translate(r#"="a b"&A1"#, "B1", "B2"), None);
+        assert_eq!(translate(r#"="a b"#, "B1", "B2"), None);
+        assert_eq!(translate("=A1\\", "B1", "B2"), None);
+        assert_eq!(translate("=2E3", "B1", "B2"), None);
+        assert_eq!(translate("=SUM(A1,", "B1", "B2"), None);
+    }
+
+    /// Stands in for a built-in, answering with its own name.
+    struct Custom(&'static str);
+
+    impl Function for Custom {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn arity(&self) -> Arity {
+            Arity::at_least(0)
+        }
+
+        fn evaluate(&self, _args: &[Value], _settings: &Settings) -> Value {
+            Value::Text(format!("custom {}", self.0))
+        }
+    }
+
+    #[test]
+    fn if_and_or_call_the_registry_unless_it_holds_the_builtin() {
+        let cells: HashMap<String, Value> = HashMap::new();
+        let run = |text: &str, functions: &Registry| evaluate_formula_with(text, &cells, functions, Settings::default());
+        assert!(matches!(run("=IF(TRUE, 1, 2)", &Registry::empty()), Value::Error(_)));
+        assert!(matches!(run("=AND(TRUE)", &Registry::empty()), Value::Error(_)));
+
+        let mut functions = Registry::new();
+        functions.register(Custom("if"));
+        functions.register(Custom("OR"));
+        assert_eq!(run("=IF(TRUE, 1, 2)", &functions).to_string(), "custom if");
+        assert_eq!(run("=OR(FALSE, TRUE)", &functions).to_string(), "custom OR");
+        // A replacement has every argument evaluated, while the built-in AND still stops at FALSE.
+        assert!(matches!(run("=OR(TRUE, 1/0)", &functions), Value::Error(_)));
+        assert_eq!(run("=AND(TRUE, FALSE, 1/0)", &functions).to_string(), "false");
+    }
+
+    #[test]
+    fn every_cell_keeps_its_column() {
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [
+            ["=1&2", 9], ["=SUM(1,2) 3", 9], ["=SUM(1,", 9], ["=)", "=1+", 9],
+            [], [1], [1, 2, "=A7+B7"], ["=LET(x, 1, x)", "=SUM(1, 2)"]]}]}"#);
+        let sheet = book.sheet("s").unwrap();
+        let widths: Vec<usize> = sheet.rows().iter().map(|r| r.len()).collect();
+        assert_eq!(widths, [2, 2, 2, 3, 0, 1, 3, 2]);
+        for address in ["A1", "A2", "A3", "A4", "B4"] {
+            assert!(matches!(book.value("s", address), Some(Value::Error(e)) if e.kind == ErrorKind::Value), "{}", address);
+        }
+        for address in ["B1", "B2", "B3", "C4"] {
+            assert_eq!(book.value("s", address).map(|v| v.to_string()), Some("9".to_string()), "{}", address);
+        }
+        assert_eq!(sheet.rows()[0][0].input(), "=1&2");
+        assert_eq!(book.value("s", "C7").map(|v| v.to_string()), Some("3".to_string()));
+        assert_eq!(book.value("s", "B8").map(|v| v.to_string()), Some("3".to_string()));
+    }
+
+    #[test]
+    fn malformed_formulas_are_value_errors_with_diagnostics() {
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [["=SUM(1,", "=SUM(1", "=)", 4]]}]}"#);
+        let error = r##"{"error":"#VALUE!","detail":"formula does not parse"}"##;
+        assert_eq!(values(&book), format!(r#"{{"results":[{{"id":"s","data":[[{0},{0},{0},4]]}}]}}"#, error));
+        let locations: Vec<&str> = book.diagnostics.iter().map(|d| d.location.as_str()).collect();
+        assert_eq!(locations, ["s!A1", "s!B1", "s!C1"]);
+        for json in [r#"{"sheets": [{"id": "s", "data": [[1 2]]}]}"#, r#"{"sheets": [{"id": "s", "data": [[1], 2]}]}"#,
+                r#"{"sheets": [{"id": "s", "data": [[1]]} 5]}"#, r#"{"sheets": [{"id": "s", "data": [["#, r#"{"sheets": [{"id": "s", "data": [[1, ""#] {
+            assert!(Workbook::from_json(json, Registry::new(), Settings::default()).is_none(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn arity_and_type_mistakes_are_reported_where_they_are_written() {
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [
+            [1, 2], [3, "=NOT(true,false)", "=IF(true)"], [4, "=GT(1)", "=NOT(1)"]]}]}"#);
+        let found: Vec<String> = book.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(found, [
+            "s!B2: wrong number of arguments to NOT: expected 1, got 2",
+            "s!C2: wrong number of arguments to IF: expected 3, got 1",
+            "s!B3: wrong number of arguments to GT: expected 2, got 1",
+            "s!C3: argument 1 of NOT must be a boolean, got a number",
+        ]);
+        for address in ["B2", "C2", "B3", "C3"] {
+            assert!(matches!(book.value("s", address), Some(Value::Error(e)) if e.kind == ErrorKind::Value), "{}", address);
+        }
+    }
+
+    #[test]
+    fn payload_numbers_take_exponents() {
+        let book = workbook(r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [[1e21, 2.5E-7, 1E+3, -4e2, "=A1*2"]]}]}"#);
+        let row: Vec<String> = ["A1", "B1", "C1", "D1", "E1"].iter().map(|a| book.value("s", a).unwrap().to_string()).collect();
+        assert_eq!(row, ["1e+21", "2.5e-7", "1000", "-400", "2e+21"]);
+        assert!(Workbook::from_json(r#"{"sheets": [{"id": "s", "data": [[1x]]}]}"#, Registry::new(), Settings::default()).is_none());
+    }
+
+    #[test]
+    fn references_stop_at_the_edge_of_the_sheet() {
+        assert_eq!(CellRef::parse("XFD1048576").map(|r| (r.row, r.col)), Some((1_048_575, 16_383)));
+        for text in ["XFE1", "A1048577", "ZZZZZZZZZZZZZZZ1", "A99999999999999999999999"] {
+            assert_eq!(CellRef::parse(text), None, "{}", text);
+        }
+        assert_eq!(translate("=ZZZZZZZZZZZZZZZ1", "A1", "A2"), None);
+        assert_eq!(translate("=XFD1", "A1", "B1"), None);
+        assert_eq!(translate("=A1048576", "A1", "A2"), None);
+        assert_eq!(translate("=XFD1048576", "B2", "A1").as_deref(), Some("=XFC1048575"));
+        assert!(matches!(formula("=ZZZZZZZZZZZZZZZ1"), Value::Error(_)));
     }
-}
 
+    #[test]
+    fn strict_and_lenient_coercion_differ_only_on_mismatched_types() {
+        let json = r#"{"submissionUrl": "x", "sheets": [{"id": "s", "data": [
+            ["=SUM(TRUE, 1)", "=SUM(\"3\", 1)", "=SUM(A2:D2)", "=SUM(D2)", "=MULTIPLY(A2:D2)", "=A2+D2", "=AND(A4:C4)", "=NPV(0.1, A3:D3)"],
+            [1, "", 2],
+            [0, 100, "", 100],
+            [true, "x", "", "=1"],
+            ["=SUM(A4:D4)"]]}]}"#;
+        let run = |coercion: Coercion| {
+            let book = Workbook::from_json(json, Registry::new(), Settings { coercion, ..Settings::default() }).unwrap();
+            let row: Vec<String> = ["A1", "B1", "C1", "D1", "E1", "F1", "G1", "A5"].iter().map(|a| book.value("s", a).unwrap().to_string())
+                .map(|v| v.split(" (").next().unwrap_or_default().to_string()).collect();
+            (row, book.value("s", "H1").unwrap().to_string())
+        };
+        let (strict, strict_npv) = run(Coercion::Strict);
+        let (lenient, lenient_npv) = run(Coercion::Lenient);
+        assert_eq!(strict, ["#VALUE!", "#VALUE!", "3", "0", "2", "1", "#VALUE!", "#VALUE!"]);
+        assert_eq!(lenient, ["2", "4", "3", "0", "2", "1", "true", "1"]);
+        assert_eq!(strict_npv, lenient_npv);
+        assert!(strict_npv.starts_with("157.776"), "{}", strict_npv);
+    }
+}
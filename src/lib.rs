@@ -0,0 +1,5 @@
+//! Spreadsheet evaluator: lexes the payload JSON, parses the formulas and evaluates them.
+//! Networking lives in the binary, behind the `net` feature.
+
+mod app;
+pub use crate::app::*;
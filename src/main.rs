@@ -1,20 +1,17 @@
-mod app;
 use std::env;
-use crate::app::*;
+use mycrate::*;
 use reqwest::Error;
-use std::cell::RefCell;
 
 async fn get_request() -> Result<(), Error> {
     let response = reqwest::get("").await?;
     println!("Status: {}", response.status());
 
     let body = response.text().await.unwrap();
-    let mut lexer = Lexer::new(body); 
-    let tokens = lexer.lex();
-    let mut parser = Parser::new(tokens);
-    let prog = parser.parse_all().unwrap();
-    let response = prog.resolver();
-    dbg!(&response);
+    let workbook = Workbook::from_json(&body, Registry::new(), Settings::default()).unwrap();
+    for diagnostic in &workbook.diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    println!("{}", serde_json::to_string(&workbook).unwrap());
     Ok(())
 }
 